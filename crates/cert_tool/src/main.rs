@@ -8,6 +8,8 @@ use std::path::PathBuf;
 use tracing::info;
 use tracing_subscriber::FmtSubscriber;
 
+mod purge;
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 #[command(propagate_version = true)]
@@ -60,6 +62,22 @@ enum Commands {
   /// Reloads the server to apply new certificates and or changes in hosts.toml
   ReloadServer {
 
+  },
+  /// Purges cached responses of the running server via its admin API
+  /// Make sure MPROXY_API_PORT and MPROXY_API_TOKEN are defined
+  Purge {
+    /// The host name the responses were requested for, optional when purging by tag
+    #[arg(long = "host", required_unless_present = "tag")]
+    host: Option<String>,
+    /// A single path with query, e.g. /index.html
+    #[arg(short = 'u', long = "url", conflicts_with_all = ["prefix", "tag"])]
+    url: Option<String>,
+    /// All paths starting with the prefix, e.g. /assets/
+    #[arg(short = 'p', long = "prefix", conflicts_with = "tag")]
+    prefix: Option<String>,
+    /// All responses tagged via the Surrogate-Key header
+    #[arg(short = 't', long = "tag")]
+    tag: Option<String>,
   },
   /// Exports certificate, private key, and hosts for a given hostname
   Export {
@@ -81,7 +99,7 @@ async fn main() {
   let cli = Cli::parse();
 
   match &cli.command {
-    Commands::CertAutoRenew { staging } => {
+    Commands::CertAutoRenew { staging: _ } => {
 
     },
     Commands::CertFind { domain } => {
@@ -114,6 +132,15 @@ async fn main() {
     }
     Commands::ReloadServer { } => {
 
+    }
+    Commands::Purge { host, url, prefix, tag } => {
+      match purge::purge_cache(host, url, prefix, tag).await {
+        Ok(response) => println!("{}", response),
+        Err(e) => {
+          eprintln!("{}", e);
+          std::process::exit(1);
+        }
+      }
     }
     Commands::Import { input_dir } => {
       letsencrypt::import_from_letsencrypt_path(input_dir).await;
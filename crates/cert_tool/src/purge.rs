@@ -0,0 +1,53 @@
+use std::time::Duration;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Sending the request and reading the whole response, purging many objects takes a while
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sends a purge request to the admin API of the running mproxy,
+/// configured by the same MPROXY_API_* variables as the server
+pub async fn purge_cache(host: &Option<String>, url: &Option<String>, prefix: &Option<String>, tag: &Option<String>) -> Result<String, String> {
+  let port = std::env::var("MPROXY_API_PORT").map_err(|_| "MPROXY_API_PORT is not set".to_string())?;
+  let token = std::env::var("MPROXY_API_TOKEN").map_err(|_| "MPROXY_API_TOKEN is not set".to_string())?;
+  let address = std::env::var("MPROXY_API_ADDRESS").unwrap_or(String::from("127.0.0.1"));
+
+  let body = json!({ "host": host, "url": url, "prefix": prefix, "tag": tag }).to_string();
+  let request = format!(
+    "POST /cache/purge HTTP/1.1\r\nHost: {}:{}\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    address,
+    port,
+    token,
+    body.len(),
+    body
+  );
+
+  let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(format!("{}:{}", address, port)))
+    .await
+    .map_err(|_| format!("Timed out connecting to the admin API at [{}:{}]", address, port))?
+    .map_err(|e| format!("Cannot connect to the admin API at [{}:{}]: {}", address, port, e))?;
+  let mut response = String::new();
+  timeout(RESPONSE_TIMEOUT, async {
+    stream.write_all(request.as_bytes()).await?;
+    stream.read_to_string(&mut response).await
+  })
+  .await
+  .map_err(|_| format!("Timed out waiting for the admin API at [{}:{}]", address, port))?
+  .map_err(|e| e.to_string())?;
+
+  let (head, body) = response.split_once("\r\n\r\n").unwrap_or((response.as_str(), ""));
+  let status = head
+    .lines()
+    .next()
+    .and_then(|status_line| status_line.split_whitespace().nth(1))
+    .and_then(|status| status.parse::<u16>().ok())
+    .unwrap_or(0);
+  if (200..300).contains(&status) {
+    Ok(body.to_string())
+  } else {
+    Err(format!("Purge failed with status [{}]: {}", status, body))
+  }
+}
@@ -0,0 +1,115 @@
+use async_trait::async_trait;
+use http::{Method, Response, StatusCode};
+use pingora::apps::http_app::ServeHttp;
+use pingora::protocols::http::ServerSession;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, warn};
+use crate::cache::{self, PurgeSelector};
+
+// Purge requests are tiny, anything bigger is rejected
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Body of `POST /cache/purge`, `host` plus at most one of `url`, `prefix` or `tag`
+#[derive(Debug, Deserialize)]
+struct PurgeRequest {
+  host: Option<String>,
+  url: Option<String>,
+  prefix: Option<String>,
+  tag: Option<String>,
+}
+
+impl PurgeRequest {
+  fn selector(&self) -> Result<PurgeSelector, &'static str> {
+    let selector = match (&self.url, &self.prefix, &self.tag) {
+      (Some(url), None, None) => PurgeSelector::Url(url.clone()),
+      (None, Some(prefix), None) => PurgeSelector::Prefix(prefix.clone()),
+      (None, None, Some(tag)) => PurgeSelector::Tag(tag.clone()),
+      (None, None, None) => PurgeSelector::Host,
+      _ => return Err("only one of url, prefix or tag may be given"),
+    };
+    if self.host.is_none() && !matches!(selector, PurgeSelector::Tag(_)) {
+      return Err("host is required unless purging by tag");
+    }
+    Ok(selector)
+  }
+}
+
+/// Authenticated HTTP API for operating the proxy, e.g. purging the cache
+pub struct AdminApi {
+  token: String,
+}
+
+impl AdminApi {
+  pub fn new(token: String) -> Self {
+    Self { token }
+  }
+
+  fn is_authorized(&self, session: &ServerSession) -> bool {
+    let Some(token) = session
+      .req_header()
+      .headers
+      .get(http::header::AUTHORIZATION)
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+      return false;
+    };
+    // Compare in constant time so the token cannot be guessed byte by byte
+    token.len() == self.token.len()
+      && token.bytes().zip(self.token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+  }
+
+  async fn read_body(session: &mut ServerSession) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    while let Some(chunk) = session.read_request_body().await.ok()? {
+      body.extend_from_slice(&chunk);
+      if body.len() > MAX_BODY_SIZE {
+        return None;
+      }
+    }
+    Some(body)
+  }
+
+  async fn purge(session: &mut ServerSession) -> (StatusCode, serde_json::Value) {
+    let Some(body) = Self::read_body(session).await else {
+      return (StatusCode::BAD_REQUEST, json!({ "error": "cannot read request body" }));
+    };
+    let request: PurgeRequest = match serde_json::from_slice(&body) {
+      Ok(request) => request,
+      Err(e) => return (StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+    };
+    let selector = match request.selector() {
+      Ok(selector) => selector,
+      Err(e) => return (StatusCode::BAD_REQUEST, json!({ "error": e })),
+    };
+    info!("Cache purge requested: {:?}", request);
+    let purged = cache::purge(request.host.as_deref(), &selector).await;
+    (StatusCode::OK, json!({ "purged": purged }))
+  }
+}
+
+#[async_trait]
+impl ServeHttp for AdminApi {
+  async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+    let (status, body) = if !self.is_authorized(session) {
+      warn!("Unauthorized admin API request: [{}]", session.req_header().uri);
+      (StatusCode::UNAUTHORIZED, json!({ "error": "unauthorized" }))
+    } else {
+      let method = session.req_header().method.clone();
+      let path = session.req_header().uri.path().to_string();
+      match (method, path.as_str()) {
+        (Method::POST, "/cache/purge") => Self::purge(session).await,
+        (_, "/cache/purge") => (StatusCode::METHOD_NOT_ALLOWED, json!({ "error": "method not allowed" })),
+        _ => (StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+      }
+    };
+    let body = body.to_string().into_bytes();
+    Response::builder()
+      .status(status)
+      .header(http::header::CONTENT_TYPE, "application/json")
+      .header(http::header::CONTENT_LENGTH, body.len())
+      .body(body)
+      .unwrap()
+  }
+}
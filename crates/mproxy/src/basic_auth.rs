@@ -0,0 +1,175 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::SystemTime;
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use argon2::Argon2;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use tracing::{error, info, warn};
+use mproxy_common::host_config::{BasicAuthConfig, HostConfigList};
+
+/// The parsed users of a htpasswd file
+struct Htpasswd {
+  modified: Option<SystemTime>,
+  users: HashMap<String, String>,
+  /// A hash of the file that unknown users are verified against, so they take as long as known ones
+  dummy_hash: Option<String>,
+  /// Authorization header values that already passed, so the (slow) hash is only verified once
+  verified: RwLock<HashSet<String>>,
+}
+
+// Parsed htpasswd files by path, loaded on config sync and reloaded in the background when they change
+static HTPASSWD_FILES: LazyLock<RwLock<HashMap<String, Arc<Htpasswd>>>> = LazyLock::new(|| {
+  info!("HTPASSWD_FILES Init");
+  RwLock::new(HashMap::new())
+});
+
+async fn modified(path: &str) -> Option<SystemTime> {
+  tokio::fs::metadata(path).await.and_then(|m| m.modified()).ok()
+}
+
+fn parse_htpasswd(path: &str, content: &str, modified: Option<SystemTime>) -> Htpasswd {
+  let mut users = HashMap::new();
+  let mut dummy_hash = None;
+  for line in content.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+    match line.split_once(':') {
+      Some((user, hash)) => {
+        dummy_hash.get_or_insert_with(|| hash.to_string());
+        users.insert(user.to_string(), hash.to_string());
+      }
+      None => warn!("Ignoring invalid line in htpasswd file [{}]", path),
+    }
+  }
+  Htpasswd { modified, users, dummy_hash, verified: RwLock::new(HashSet::new()) }
+}
+
+async fn load_htpasswd(path: &str, modified: Option<SystemTime>) -> Htpasswd {
+  match tokio::fs::read_to_string(path).await {
+    Ok(content) => {
+      let htpasswd = parse_htpasswd(path, &content, modified);
+      info!("Loaded [{}] users from htpasswd file [{}]", htpasswd.users.len(), path);
+      htpasswd
+    }
+    Err(e) => {
+      error!("Cannot read htpasswd file [{}]: {}", path, e);
+      parse_htpasswd(path, "", modified)
+    }
+  }
+}
+
+/// Loads the htpasswd files of the config that are new or changed and drops the ones no longer used
+pub async fn sync_files(host_config_list: &HostConfigList) {
+  let mut paths = HashSet::new();
+  for host_config in &host_config_list.host_configs {
+    let locations = host_config.locations.iter().flatten();
+    for auth in host_config.basic_auth.iter().chain(locations.filter_map(|l| l.basic_auth.as_ref())) {
+      paths.insert(auth.htpasswd_file.clone());
+    }
+  }
+  for path in &paths {
+    reload_if_changed(path).await;
+  }
+  HTPASSWD_FILES.write().unwrap().retain(|path, _| paths.contains(path));
+}
+
+/// Reloads the htpasswd files that changed on disk
+pub async fn reload_changed_files() {
+  let paths: Vec<String> = HTPASSWD_FILES.read().unwrap().keys().cloned().collect();
+  for path in paths {
+    reload_if_changed(&path).await;
+  }
+}
+
+async fn reload_if_changed(path: &str) {
+  let modified = modified(path).await;
+  let current = HTPASSWD_FILES.read().unwrap().get(path).map(|htpasswd| htpasswd.modified);
+  if current != Some(modified) {
+    let htpasswd = Arc::new(load_htpasswd(path, modified).await);
+    HTPASSWD_FILES.write().unwrap().insert(path.to_string(), htpasswd);
+  }
+}
+
+fn verify_hash(user: &str, password: &str, hash: &str) -> bool {
+  if hash.starts_with("$2") {
+    // htpasswd -B writes $2y$, which the bcrypt crate verifies like $2b$
+    return bcrypt::verify(password, hash).unwrap_or_else(|e| {
+      error!("Invalid bcrypt hash for user [{}]: {}", user, e);
+      false
+    });
+  }
+  if hash.starts_with("$argon2") {
+    return match PasswordHash::new(hash) {
+      Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+      Err(e) => {
+        error!("Invalid argon2 hash for user [{}]: {}", user, e);
+        false
+      }
+    };
+  }
+  warn!("Unsupported password hash for user [{}], only bcrypt and argon2 are supported", user);
+  false
+}
+
+/// Whether the Authorization header of a request carries valid credentials of the htpasswd file
+pub async fn is_authorized(config: &BasicAuthConfig, authorization: Option<&str>) -> bool {
+  let Some(authorization) = authorization else {
+    return false;
+  };
+  let Some(credentials) = authorization.strip_prefix("Basic ").map(str::trim) else {
+    return false;
+  };
+  let Some((user, password)) = STANDARD
+    .decode(credentials)
+    .ok()
+    .and_then(|decoded| String::from_utf8(decoded).ok())
+    .and_then(|decoded| decoded.split_once(':').map(|(u, p)| (u.to_string(), p.to_string())))
+  else {
+    return false;
+  };
+
+  let Some(htpasswd) = HTPASSWD_FILES.read().unwrap().get(&config.htpasswd_file).cloned() else {
+    error!("Htpasswd file [{}] is not loaded", config.htpasswd_file);
+    return false;
+  };
+  if htpasswd.verified.read().unwrap().contains(authorization) {
+    return true;
+  }
+  let (hash, known_user) = match (htpasswd.users.get(&user), &htpasswd.dummy_hash) {
+    (Some(hash), _) => (hash.clone(), true),
+    (None, Some(dummy_hash)) => (dummy_hash.clone(), false),
+    (None, None) => {
+      info!("Basic auth failed for unknown user [{}]", user);
+      return false;
+    }
+  };
+
+  // Hash verification is CPU heavy and must not block the proxy threads
+  let verify_user = user.clone();
+  let valid = tokio::task::spawn_blocking(move || verify_hash(&verify_user, &password, &hash))
+    .await
+    .unwrap_or(false)
+    && known_user;
+  if valid {
+    htpasswd.verified.write().unwrap().insert(authorization.to_string());
+  } else if known_user {
+    info!("Basic auth failed for user [{}]", user);
+  } else {
+    info!("Basic auth failed for unknown user [{}]", user);
+  }
+  valid
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_users_and_keeps_a_dummy_hash() {
+    let content = "# users\nalice:$2y$05$first\n\nbob:$argon2id$second\ninvalid line\n";
+    let htpasswd = parse_htpasswd("htpasswd", content, None);
+    assert_eq!(htpasswd.users.len(), 2);
+    assert_eq!(htpasswd.users.get("bob").map(String::as_str), Some("$argon2id$second"));
+    assert_eq!(htpasswd.dummy_hash.as_deref(), Some("$2y$05$first"));
+    assert!(parse_htpasswd("htpasswd", "", None).dummy_hash.is_none());
+  }
+}
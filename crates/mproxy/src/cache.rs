@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, SystemTime};
+use pingora::cache::cache_control::CacheControl;
+use pingora::cache::eviction::{simple_lru, EvictionManager};
+use pingora::cache::filters::resp_cacheable;
+use pingora::cache::key::{CacheHashKey, CompactCacheKey, HashBinary};
+use pingora::cache::lock::CacheLock;
+use pingora::cache::storage::{PurgeType, Storage};
+use pingora::cache::trace::Span;
+use pingora::cache::{CacheKey, CacheMeta, CacheMetaDefaults, CachePhase, MemCache, NoCacheReason, RespCacheable, VarianceBuilder};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+use tracing::{error, info};
+use mproxy_common::cache_path;
+use mproxy_common::host_config::{CacheConfig, CacheStorage, HostConfigList};
+use crate::disk_cache::DiskCache;
+
+// Freshness only comes from the upstream headers, the default TTL of a host is applied separately
+const NO_DEFAULTS: CacheMetaDefaults = CacheMetaDefaults::new(|_| None, 0, 0);
+
+fn size_limit(var: &str, default_mb: usize) -> usize {
+  std::env::var(var)
+    .ok()
+    .and_then(|mb| mb.parse::<usize>().ok())
+    .unwrap_or(default_mb)
+    * 1024
+    * 1024
+}
+
+static MEMORY_STORAGE: LazyLock<MemCache> = LazyLock::new(|| {
+  info!("MEMORY_STORAGE Init");
+  MemCache::new()
+});
+
+static MEMORY_EVICTION: LazyLock<simple_lru::Manager> =
+  LazyLock::new(|| simple_lru::Manager::new(size_limit("MPROXY_CACHE_MEMORY_MB", 256)));
+
+// Lets one request per key fetch or revalidate while the others wait or get the stale response,
+// pingora only revalidates in the background for the lock holder
+static CACHE_LOCK: LazyLock<CacheLock> = LazyLock::new(|| {
+  info!("CACHE_LOCK Init");
+  CacheLock::new(Duration::from_secs(10))
+});
+
+static DISK_STORAGE: LazyLock<DiskCache> = LazyLock::new(|| {
+  info!("DISK_STORAGE Init");
+  DiskCache::new(PathBuf::from(cache_path()))
+});
+
+static DISK_EVICTION: LazyLock<simple_lru::Manager> =
+  LazyLock::new(|| simple_lru::Manager::new(size_limit("MPROXY_CACHE_DISK_MB", 4096)));
+
+/// A cached response as known to the purge API
+struct IndexedObject {
+  key: CompactCacheKey,
+  storage: CacheStorage,
+  tags: Vec<String>,
+  /// After this the storage does not serve the object anymore, even stale
+  expires: SystemTime,
+}
+
+/// Indexed objects by path with query
+type PathIndex = HashMap<String, Vec<IndexedObject>>;
+
+struct CacheIndex {
+  by_host: HashMap<String, PathIndex>,
+  /// Number of indexed objects
+  len: usize,
+  /// Size at which the expired objects are removed, evicted objects stay indexed until they expire
+  prune_at: usize,
+}
+
+impl CacheIndex {
+  fn insert(&mut self, server_name: &str, path_and_query: String, object: IndexedObject) {
+    let objects = self.by_host.entry(server_name.to_string()).or_default().entry(path_and_query).or_default();
+    // Variants of a path (Vary) have their own key
+    let before = objects.len();
+    objects.retain(|indexed| indexed.key.combined() != object.key.combined());
+    self.len -= before - objects.len();
+    objects.push(object);
+    self.len += 1;
+    if self.len >= self.prune_at {
+      self.prune(SystemTime::now());
+    }
+  }
+
+  fn prune(&mut self, now: SystemTime) {
+    for paths in self.by_host.values_mut() {
+      paths.values_mut().for_each(|objects| objects.retain(|indexed| indexed.expires > now));
+      paths.retain(|_, objects| !objects.is_empty());
+    }
+    self.by_host.retain(|_, paths| !paths.is_empty());
+    self.len = self.by_host.values().flat_map(|paths| paths.values()).map(Vec::len).sum();
+    self.prune_at = (self.len * 2).max(MIN_INDEX_PRUNE_SIZE);
+  }
+}
+
+// Cached objects by host name and path with query, pingora's storages cannot be listed
+static CACHE_INDEX: LazyLock<Mutex<CacheIndex>> = LazyLock::new(|| {
+  info!("CACHE_INDEX Init");
+  Mutex::new(CacheIndex {
+    by_host: HashMap::new(),
+    len: 0,
+    prune_at: MIN_INDEX_PRUNE_SIZE,
+  })
+});
+
+// How many objects the index holds before the expired ones are removed
+const MIN_INDEX_PRUNE_SIZE: usize = 1024;
+
+/// Selects the cached objects of a host to purge
+pub enum PurgeSelector {
+  /// A single path with query
+  Url(String),
+  /// All paths starting with the prefix
+  Prefix(String),
+  /// Everything of the host
+  Host,
+  /// Objects whose tag header contained the tag
+  Tag(String),
+}
+
+/// Sets up the disk storage at startup when a host or location caches on disk,
+/// clearing it is blocking I/O that must not run on a proxy worker
+pub fn init(host_config_list: &HostConfigList) {
+  let uses_disk = host_config_list.host_configs.iter().any(|host_config| {
+    host_config
+      .cache
+      .iter()
+      .chain(host_config.locations.iter().flatten().filter_map(|location| location.cache.as_ref()))
+      .any(|config| config.storage == CacheStorage::Disk)
+  });
+  if uses_disk {
+    LazyLock::force(&DISK_STORAGE);
+  }
+}
+
+/// Turns on caching for the request in the storage of the config
+pub fn enable(session: &mut Session, config: &CacheConfig) {
+  match config.storage {
+    CacheStorage::Memory => session.cache.enable(&*MEMORY_STORAGE, Some(&*MEMORY_EVICTION), None, Some(&*CACHE_LOCK), None),
+    CacheStorage::Disk => session.cache.enable(&*DISK_STORAGE, Some(&*DISK_EVICTION), None, Some(&*CACHE_LOCK), None),
+  }
+  if let Some(max_object_size) = config.max_object_size {
+    session.cache.set_max_file_size_bytes(max_object_size);
+  }
+}
+
+/// Responses are cached per requested host name and path with query
+pub fn cache_key(req: &RequestHeader, server_name: &str) -> CacheKey {
+  let path_and_query = req.uri.path_and_query().map_or("/", |pq| pq.as_str());
+  CacheKey::new(server_name, path_and_query, "")
+}
+
+/// Decides whether and how long an upstream response is cached
+pub fn response_cacheable(req: &RequestHeader, resp: &ResponseHeader, config: &CacheConfig) -> RespCacheable {
+  // A shared cache must not hand out cookies of one client to others
+  if resp.headers.contains_key(http::header::SET_COOKIE) {
+    return RespCacheable::Uncacheable(NoCacheReason::Custom("set-cookie"));
+  }
+  let vary_all = resp
+    .headers
+    .get_all(http::header::VARY)
+    .iter()
+    .any(|vary| vary.to_str().is_ok_and(|vary| vary.split(',').any(|name| name.trim() == "*")));
+  if vary_all {
+    return RespCacheable::Uncacheable(NoCacheReason::Custom("vary *"));
+  }
+
+  let authorization = req.headers.contains_key(http::header::AUTHORIZATION);
+  let cache_control = CacheControl::from_resp_headers(resp);
+  let cacheable = match resp_cacheable(cache_control.as_ref(), resp.clone(), authorization, &NO_DEFAULTS) {
+    RespCacheable::Cacheable(meta) => return RespCacheable::Cacheable(with_staleness(meta, config)),
+    uncacheable => uncacheable,
+  };
+
+  // Without any caching headers of the upstream the default TTL of the host applies
+  let no_freshness = !resp.headers.contains_key(http::header::CACHE_CONTROL) && !resp.headers.contains_key(http::header::EXPIRES);
+  let heuristically_cacheable = matches!(resp.status.as_u16(), 200 | 203 | 301 | 404 | 410);
+  match config.default_ttl_secs {
+    Some(ttl) if no_freshness && heuristically_cacheable && !authorization => {
+      let now = SystemTime::now();
+      let meta = CacheMeta::new(now + Duration::from_secs(ttl as u64), now, 0, 0, resp.clone());
+      RespCacheable::Cacheable(with_staleness(meta, config))
+    }
+    _ => cacheable,
+  }
+}
+
+/// Applies the stale windows of the host, stale-while-revalidate / stale-if-error directives
+/// of the upstream can only shorten them
+fn with_staleness(meta: CacheMeta, config: &CacheConfig) -> CacheMeta {
+  let limit = |upstream: u32, host: u32| if upstream > 0 { upstream.min(host) } else { host };
+  CacheMeta::new(
+    meta.fresh_until(),
+    meta.created(),
+    limit(meta.stale_while_revalidate_sec(), config.stale_while_revalidate_secs()),
+    limit(meta.stale_if_error_sec(), config.stale_if_error_secs()),
+    meta.response_header().clone(),
+  )
+}
+
+/// Whether an expired response may be served instead of waiting for (`error` is `None`)
+/// or failing with the upstream. The stale windows themselves are checked by pingora
+pub fn should_serve_stale(config: &CacheConfig, error: Option<&Error>) -> bool {
+  match error {
+    None => config.stale_while_revalidate_secs() > 0,
+    Some(e) => config.stale_if_error_secs() > 0 && (*e.esource() == ErrorSource::Upstream || matches!(e.etype(), HTTPStatus(500..=599))),
+  }
+}
+
+/// The variance of a cached response by the request headers named in its Vary header
+pub fn variance(meta: &CacheMeta, req: &RequestHeader) -> Option<HashBinary> {
+  let names: Vec<String> = meta
+    .headers()
+    .get_all(http::header::VARY)
+    .iter()
+    .filter_map(|vary| vary.to_str().ok())
+    .flat_map(|vary| vary.split(','))
+    .map(|name| name.trim().to_ascii_lowercase())
+    .filter(|name| !name.is_empty())
+    .collect();
+  let mut variance = VarianceBuilder::new();
+  for name in &names {
+    let value = req.headers.get(name.as_str()).map_or(&b""[..], |v| v.as_bytes());
+    variance.add_value(name, value);
+  }
+  variance.finalize()
+}
+
+/// Value of the X-Cache header of a response served with caching enabled
+pub fn status(session: &Session) -> &'static str {
+  match session.cache.phase() {
+    CachePhase::Hit | CachePhase::Revalidated => "HIT",
+    CachePhase::Stale | CachePhase::StaleUpdating => "STALE",
+    CachePhase::Miss | CachePhase::Expired => "MISS",
+    _ => "BYPASS",
+  }
+}
+
+/// Remembers a response that was just written to the cache so it can be purged later
+pub fn index_response(session: &Session, config: &CacheConfig, server_name: &str) {
+  if !matches!(session.cache.phase(), CachePhase::Miss | CachePhase::Expired | CachePhase::Revalidated) {
+    return;
+  }
+  let Some(meta) = session.cache.maybe_cache_meta() else {
+    return;
+  };
+  let key = session.cache.cache_key().to_compact();
+  let tags = meta
+    .headers()
+    .get_all(config.tag_header())
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(|c: char| c == ',' || c.is_whitespace()))
+    .filter(|tag| !tag.is_empty())
+    .map(str::to_string)
+    .collect();
+  let stale = meta.stale_while_revalidate_sec().max(meta.stale_if_error_sec());
+  let object = IndexedObject {
+    key,
+    storage: config.storage,
+    tags,
+    expires: meta.fresh_until() + Duration::from_secs(stale as u64),
+  };
+  let path_and_query = session.req_header().uri.path_and_query().map_or("/", |pq| pq.as_str()).to_string();
+
+  CACHE_INDEX.lock().unwrap().insert(server_name, path_and_query, object);
+}
+
+/// Removes the selected cached objects of a host (or of all hosts for a tag without host),
+/// returns how many were purged
+pub async fn purge(host: Option<&str>, selector: &PurgeSelector) -> usize {
+  let objects: Vec<IndexedObject> = {
+    let mut index = CACHE_INDEX.lock().unwrap();
+    let mut selected = Vec::new();
+    for (_, paths) in index.by_host.iter_mut().filter(|(name, _)| host.is_none_or(|host| host == name.as_str())) {
+      for (path_and_query, objects) in paths.iter_mut() {
+        match selector {
+          PurgeSelector::Url(url) if path_and_query == url => selected.append(objects),
+          PurgeSelector::Prefix(prefix) if path_and_query.starts_with(prefix.as_str()) => selected.append(objects),
+          PurgeSelector::Host => selected.append(objects),
+          PurgeSelector::Tag(tag) => {
+            let (tagged, untagged): (Vec<IndexedObject>, Vec<IndexedObject>) =
+              std::mem::take(objects).into_iter().partition(|object| object.tags.contains(tag));
+            *objects = untagged;
+            selected.extend(tagged);
+          }
+          _ => {}
+        }
+      }
+      paths.retain(|_, objects| !objects.is_empty());
+    }
+    index.by_host.retain(|_, paths| !paths.is_empty());
+    index.len -= selected.len();
+    selected
+  };
+
+  let span = Span::inactive().handle();
+  let mut purged = 0;
+  for object in objects {
+    let result = match object.storage {
+      CacheStorage::Memory => {
+        MEMORY_EVICTION.remove(&object.key);
+        MEMORY_STORAGE.purge(&object.key, PurgeType::Invalidation, &span).await
+      }
+      CacheStorage::Disk => {
+        DISK_EVICTION.remove(&object.key);
+        DISK_STORAGE.purge(&object.key, PurgeType::Invalidation, &span).await
+      }
+    };
+    match result {
+      Ok(true) => purged += 1,
+      Ok(false) => {}
+      Err(e) => error!("Cannot purge cached object: {}", e),
+    }
+  }
+  info!("Purged [{}] cached objects of [{}]", purged, host.unwrap_or("all hosts"));
+  purged
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn object(path: &str, expires: SystemTime) -> IndexedObject {
+    IndexedObject {
+      key: CacheKey::new("example.com", path, "").to_compact(),
+      storage: CacheStorage::Memory,
+      tags: Vec::new(),
+      expires,
+    }
+  }
+
+  #[test]
+  fn index_removes_expired_objects_when_reaching_the_prune_size() {
+    let mut index = CacheIndex { by_host: HashMap::new(), len: 0, prune_at: MIN_INDEX_PRUNE_SIZE };
+    let expired = SystemTime::now() - Duration::from_secs(1);
+    let fresh = SystemTime::now() + Duration::from_secs(3600);
+    for i in 0..MIN_INDEX_PRUNE_SIZE - 2 {
+      index.insert("example.com", format!("/expired/{}", i), object(&format!("/expired/{}", i), expired));
+    }
+    // Replacing the object of a key keeps the count
+    index.insert("example.com", "/fresh".to_string(), object("/fresh", fresh));
+    index.insert("example.com", "/fresh".to_string(), object("/fresh", fresh));
+    assert_eq!(index.len, MIN_INDEX_PRUNE_SIZE - 1);
+
+    index.insert("example.com", "/fresh/2".to_string(), object("/fresh/2", fresh));
+    assert_eq!(index.len, 2);
+    assert_eq!(index.by_host["example.com"].len(), 2);
+    assert_eq!(index.prune_at, MIN_INDEX_PRUNE_SIZE);
+  }
+}
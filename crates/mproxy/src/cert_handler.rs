@@ -34,61 +34,65 @@ impl CertHandler {
 impl pingora::listeners::TlsAccept for CertHandler {
   async fn certificate_callback(&self, _ssl: &mut TlsRef) -> () {
     // Store the servername in an owned String to avoid borrowing _ssl
-    let servername = _ssl.servername(NameType::HOST_NAME).map(|s| s.to_string());
-    match servername {
-      Some(servername) => {
-        if let Some(certificate) = self.find_cert(&servername) {
-          if let Some(cert_fullchain) = certificate.full_chain {
-            match X509::from_pem(cert_fullchain.as_bytes()) {
-              Ok(cert) => {
-                _ssl.set_certificate(&cert).unwrap();
-                _ssl.add_chain_cert(cert).unwrap();
-              }
-              Err(e) => {
-                error!("Error loading cert: {}", e);
-              }
-            };
+    let servername = _ssl
+      .servername(NameType::HOST_NAME)
+      .map(|s| s.to_string())
+      .unwrap_or_default();
+    // Unknown or missing server names get the certificate of the default host (if any)
+    let certificate = match self.find_cert(&servername) {
+      Some(certificate) => certificate,
+      None => match self.cert_store.get_default_cert() {
+        Some(certificate) => certificate,
+        None => {
+          // NO CERT for HOSTNAME found
+          if servername.is_empty() {
+            error!("No Server Hostname set");
           } else {
-            error!("No full chain for: [{}]", servername);
-            return;
+            error!("No Certificate for: [{}]", servername);
           }
+          return;
+        }
+      },
+    };
 
-          if let Some(intermediate_cert) = &*certificate.parsed_inter_cert.borrow() {
-            match X509::from_pem(intermediate_cert) {
-              Ok(cert) => {
-                _ssl.add_chain_cert(cert).unwrap();
-              }
-              Err(e) => {
-                error!("Error loading intermediate cert: {}", e);
-                return;
-              }
-            };
-          }
+    if let Some(cert_fullchain) = certificate.full_chain {
+      match X509::from_pem(cert_fullchain.as_bytes()) {
+        Ok(cert) => {
+          _ssl.set_certificate(&cert).unwrap();
+          _ssl.add_chain_cert(cert).unwrap();
+        }
+        Err(e) => {
+          error!("Error loading cert: {}", e);
+        }
+      };
+    } else {
+      error!("No full chain for: [{}]", servername);
+      return;
+    }
 
-          if let Some(key_pem) = certificate.private_key_pem {
-            let loaded_key = match PKey::private_key_from_pem(key_pem.as_bytes()) {
-              Ok(key) => key,
-              Err(e) => {
-                error!("Error loading key: {}", e);
-                return;
-              }
-            };
-            _ssl.set_private_key(&loaded_key).unwrap();
-          } else {
-            error!("No private key for: [{}]", servername);
-            return;
-          }
+    if let Some(intermediate_cert) = &*certificate.parsed_inter_cert.borrow() {
+      match X509::from_pem(intermediate_cert) {
+        Ok(cert) => {
+          _ssl.add_chain_cert(cert).unwrap();
+        }
+        Err(e) => {
+          error!("Error loading intermediate cert: {}", e);
+          return;
+        }
+      };
+    }
 
-        } else {
-          // NO CERT for HOSTNAME found
-          error!("No Certificate for: [{}]", servername);
+    if let Some(key_pem) = certificate.private_key_pem {
+      let loaded_key = match PKey::private_key_from_pem(key_pem.as_bytes()) {
+        Ok(key) => key,
+        Err(e) => {
+          error!("Error loading key: {}", e);
           return;
         }
-      }
-      _ => {
-        error!("No Server Hostname set");
-      }
-    };
-    // todo!()
+      };
+      _ssl.set_private_key(&loaded_key).unwrap();
+    } else {
+      error!("No private key for: [{}]", servername);
+    }
   }
 }
@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::{LazyLock, Mutex};
-use tracing::info;
+use regex::Regex;
+use tracing::{error, info};
 use mproxy_common::cert_path;
 use mproxy_common::certificates::Certificate;
-use mproxy_common::host_config::{HostConfig, HostConfigList, HostsConfigLoader};
+use mproxy_common::host_config::{ErrorPagesConfig, HostConfig, HostConfigList, HostsConfigLoader, SecurityHeadersConfig, UnknownHostConfig};
 
 // This is a Global Certificate Map that is used by the CertHandler
 static CERT_MAP: LazyLock<Mutex<HashMap<String, Option<Certificate>>>> = LazyLock::new(|| {
@@ -12,7 +13,33 @@ static CERT_MAP: LazyLock<Mutex<HashMap<String, Option<Certificate>>>> = LazyLoc
   Mutex::new(HashMap::new())
 });
 
+/// A compiled regex host name and its certificate
+type CertPattern = (Regex, Option<Certificate>);
 
+// Regex host names ("~pattern") in config order, only checked when no exact or wildcard name matches
+static CERT_PATTERNS: LazyLock<Mutex<Vec<CertPattern>>> = LazyLock::new(|| {
+  info!("CERT_PATTERNS Init");
+  Mutex::new(Vec::new())
+});
+
+
+// The default host and the handling of unknown host names from hosts.toml
+static UNKNOWN_HOST: LazyLock<Mutex<(Option<String>, Option<UnknownHostConfig>)>> = LazyLock::new(|| {
+  info!("UNKNOWN_HOST Init");
+  Mutex::new((None, None))
+});
+
+// The global error pages from hosts.toml
+static ERROR_PAGES: LazyLock<Mutex<Option<ErrorPagesConfig>>> = LazyLock::new(|| {
+  info!("ERROR_PAGES Init");
+  Mutex::new(None)
+});
+
+// The global security headers from hosts.toml
+static SECURITY_HEADERS: LazyLock<Mutex<Option<SecurityHeadersConfig>>> = LazyLock::new(|| {
+  info!("SECURITY_HEADERS Init");
+  Mutex::new(None)
+});
 
 #[derive(Debug)]
 pub struct CertStore {
@@ -28,65 +55,157 @@ impl CertStore {
     }
   }
 
-  pub fn refresh_hosts(&mut self) {
-    if let Some(host_config_loader) = &mut self.host_config_loader {
-      host_config_loader.refresh_hosts_config();
+  /// Reloads hosts.toml when it changed and rebuilds the certificate map, returns the new config
+  /// Reloads hosts.toml when it changed, requests see the new config once it is passed to
+  /// `load_certs_from_host_config_list`
+  pub fn refresh_hosts(&mut self) -> Option<HostConfigList> {
+    let host_config_loader = self.host_config_loader.as_mut()?;
+    if !host_config_loader.refresh_hosts_config() {
+      return None;
     }
+    Some(host_config_loader.load())
   }
   pub fn set_host_config_loader(&mut self, host_config_loader: HostsConfigLoader) {
-    self.host_config_loader = Some(host_config_loader.into());
+    self.host_config_loader = Some(host_config_loader);
   }
 
   pub fn load_certs_from_host_config_list(&self, host_config_list: &HostConfigList) {
+    // Build the new maps first so lookups never see a partially loaded config
+    let mut map = HashMap::new();
+    let mut patterns = Vec::new();
     host_config_list.host_configs.iter().for_each(|host_config| {
-      self.host_config_to_cert(host_config);
+      self.host_config_to_cert(host_config, &mut map, &mut patterns);
     });
+    *CERT_MAP.lock().unwrap() = map;
+    *CERT_PATTERNS.lock().unwrap() = patterns;
+    *UNKNOWN_HOST.lock().unwrap() = (host_config_list.default_host.clone(), host_config_list.unknown_host.clone());
+    *ERROR_PAGES.lock().unwrap() = host_config_list.error_pages.clone();
+    *SECURITY_HEADERS.lock().unwrap() = host_config_list.security_headers.clone();
   }
 
-  fn host_config_to_cert(&self, host_config: &HostConfig) {
-    let mut map = CERT_MAP.lock().unwrap();
+  fn host_config_to_cert(
+    &self,
+    host_config: &HostConfig,
+    map: &mut HashMap<String, Option<Certificate>>,
+    patterns: &mut Vec<(Regex, Option<Certificate>)>,
+  ) {
     let cert_path = PathBuf::from(cert_path())
       .join(cert_path())
-      .join(host_config.host_name.clone())
+      .join(host_config.certificate_name())
       .join("cert.json");
     let mut cert = Some(Certificate::from_path(cert_path));
     cert.as_mut().unwrap().host_config = Some(host_config.clone());
-    map.insert(host_config.host_name.clone(), cert.clone());
-    if let Some(aliases) = &host_config.aliases {
-      for alias in aliases {
-        map.insert(alias.clone(), cert.clone());
+    for host_name in host_config.host_names() {
+      match host_name.strip_prefix('~') {
+        Some(pattern) => match Regex::new(pattern) {
+          Ok(regex) => patterns.push((regex, cert.clone())),
+          Err(e) => error!("Invalid host name pattern [{}]: {}", host_name, e),
+        },
+        None => {
+          map.insert(host_name, cert.clone());
+        }
       }
     }
   }
 
-  pub fn set_cert(&self, server_name: &str, cert: Certificate) {
-    let mut map = CERT_MAP.lock().unwrap();
-    map.insert(server_name.to_string(), Some(cert.clone()));
-  }
-
+  /// Finds the certificate (and host config) of a server name: exact names first,
+  /// then a single label wildcard (`*.example.com`), then the regex host names
   pub fn get_cert(&self, server_name: &str) -> Option<Certificate> {
-    let map = CERT_MAP.lock().unwrap();
-    match map.get(server_name) {
-      Some(cert) => cert.to_owned(),
-      None => None,
+    if let Some(cert) = find_named_cert(&CERT_MAP.lock().unwrap(), server_name) {
+      return cert;
     }
+    find_pattern_cert(&CERT_PATTERNS.lock().unwrap(), server_name)
   }
 
-  fn extract_last_cert(pem_chain: &str) -> Option<&str> {
-    let begin_marker = "-----BEGIN CERTIFICATE-----";
-    let end_marker = "-----END CERTIFICATE-----";
-    let mut last_pos = 0;
-    while let Some(begin) = pem_chain[last_pos..].find(begin_marker) {
-      let begin = last_pos + begin;
-      let end = pem_chain[begin..].find(end_marker)?;
-      last_pos = begin + end + end_marker.len();
-    }
-    // Get the last certificate block if it exists
-    if last_pos > 0 {
-      let begin = pem_chain[..last_pos].rfind(begin_marker)?;
-      let end = pem_chain[begin..].find(end_marker)? + end_marker.len() + begin;
-      return Some(&pem_chain[begin..end]);
+  /// The certificate of the default host, used for unknown or missing server names
+  pub fn get_default_cert(&self) -> Option<Certificate> {
+    let default_host = UNKNOWN_HOST.lock().unwrap().0.clone()?;
+    self.get_cert(&default_host)
+  }
+
+  pub fn unknown_host_config(&self) -> Option<UnknownHostConfig> {
+    UNKNOWN_HOST.lock().unwrap().1.clone()
+  }
+
+  pub fn error_pages(&self) -> Option<ErrorPagesConfig> {
+    ERROR_PAGES.lock().unwrap().clone()
+  }
+
+  /// The security headers of a host, its own settings before the global ones and the defaults
+  pub fn security_headers(&self, host_config: Option<&HostConfig>) -> SecurityHeadersConfig {
+    let global = SECURITY_HEADERS.lock().unwrap().clone().unwrap_or_default();
+    match host_config.and_then(|host_config| host_config.security_headers.as_ref()) {
+      Some(security_headers) => security_headers.merge(&global),
+      None => global,
     }
-    None
+  }
+}
+
+/// The entry of an exact or single label wildcard name, `None` when neither is configured
+fn find_named_cert(map: &HashMap<String, Option<Certificate>>, server_name: &str) -> Option<Option<Certificate>> {
+  if let Some(cert) = map.get(server_name) {
+    return Some(cert.to_owned());
+  }
+  let (_, parent) = server_name.split_once('.')?;
+  map.get(&format!("*.{}", parent)).map(|cert| cert.to_owned())
+}
+
+fn find_pattern_cert(patterns: &[CertPattern], server_name: &str) -> Option<Certificate> {
+  patterns
+    .iter()
+    .find(|(regex, _)| regex.is_match(server_name))
+    .and_then(|(_, cert)| cert.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::cell::RefCell;
+
+  fn cert(host_name: &str) -> Option<Certificate> {
+    Some(Certificate {
+      host_name: host_name.to_string(),
+      host_names: None,
+      private_key_pem: None,
+      certificate_pem: None,
+      full_chain: None,
+      host_config: None,
+      parsed_cert_der: RefCell::new(None),
+      parsed_inter_cert: RefCell::new(None),
+    })
+  }
+
+  fn found(map: &HashMap<String, Option<Certificate>>, server_name: &str) -> Option<String> {
+    find_named_cert(map, server_name).flatten().map(|cert| cert.host_name)
+  }
+
+  #[test]
+  fn named_certs_match_exact_names_before_single_label_wildcards() {
+    let map: HashMap<String, Option<Certificate>> = [
+      ("example.com".to_string(), cert("example.com")),
+      ("*.example.com".to_string(), cert("*.example.com")),
+      ("api.example.com".to_string(), cert("api.example.com")),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(found(&map, "example.com").as_deref(), Some("example.com"));
+    assert_eq!(found(&map, "api.example.com").as_deref(), Some("api.example.com"));
+    assert_eq!(found(&map, "www.example.com").as_deref(), Some("*.example.com"));
+    // The wildcard covers exactly one label
+    assert_eq!(found(&map, "a.b.example.com"), None);
+    assert_eq!(found(&map, "other.com"), None);
+    assert_eq!(found(&map, "localhost"), None);
+  }
+
+  #[test]
+  fn pattern_certs_match_in_config_order() {
+    let patterns: Vec<CertPattern> = vec![
+      (Regex::new(r"^[a-z]+\.preview\.example\.com$").unwrap(), cert("preview")),
+      (Regex::new(r"\.example\.com$").unwrap(), cert("fallback")),
+    ];
+    let found = |server_name| find_pattern_cert(&patterns, server_name).map(|cert| cert.host_name);
+    assert_eq!(found("pr.preview.example.com").as_deref(), Some("preview"));
+    assert_eq!(found("a.b.example.com").as_deref(), Some("fallback"));
+    assert_eq!(found("example.org"), None);
   }
 }
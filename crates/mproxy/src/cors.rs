@@ -0,0 +1,79 @@
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+use mproxy_common::host_config::{CorsConfig, HostConfig};
+use crate::cert_store::CertStore;
+use crate::security_headers;
+
+fn header<'a>(req: &'a RequestHeader, name: &str) -> Option<&'a str> {
+  req.headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+pub fn is_preflight(req: &RequestHeader) -> bool {
+  req.method == http::Method::OPTIONS
+    && req.headers.contains_key(http::header::ORIGIN)
+    && req.headers.contains_key(http::header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Answers a preflight request, with 403 for origins that are not allowed
+pub async fn respond_preflight(session: &mut Session, cert_store: &CertStore, host_config: &HostConfig, cors: &CorsConfig) -> Result<()> {
+  let req = session.req_header();
+  let origin = header(req, "Origin").unwrap_or("");
+  if !cors.allows_origin(origin) {
+    let mut response_header = ResponseHeader::build(403, Some(1))?;
+    response_header.insert_header(http::header::CONTENT_LENGTH, "0")?;
+    security_headers::apply(&mut response_header, cert_store, Some(host_config))?;
+    return session.write_response_header(Box::new(response_header), true).await;
+  }
+  let mut response_header = ResponseHeader::build(204, Some(7))?;
+  response_header.insert_header(http::header::ACCESS_CONTROL_ALLOW_ORIGIN, cors.allow_origin_value(origin).to_string())?;
+  response_header.insert_header(http::header::ACCESS_CONTROL_ALLOW_METHODS, cors.allowed_methods())?;
+  let allowed_headers = match &cors.allowed_headers {
+    Some(headers) => Some(headers.join(", ")),
+    None => header(req, "Access-Control-Request-Headers").map(str::to_string),
+  };
+  if let Some(allowed_headers) = allowed_headers {
+    response_header.insert_header(http::header::ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers)?;
+  }
+  if cors.allow_credentials() {
+    response_header.insert_header(http::header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")?;
+  }
+  if let Some(max_age) = cors.max_age_secs {
+    response_header.insert_header(http::header::ACCESS_CONTROL_MAX_AGE, max_age.to_string())?;
+  }
+  response_header.insert_header(http::header::VARY, "Origin, Access-Control-Request-Method, Access-Control-Request-Headers")?;
+  response_header.insert_header(http::header::CONTENT_LENGTH, "0")?;
+  security_headers::apply(&mut response_header, cert_store, Some(host_config))?;
+  session.write_response_header(Box::new(response_header), true).await
+}
+
+/// Adds the CORS headers of a host with a CORS policy to a response written by mproxy itself
+pub fn apply(req: &RequestHeader, resp: &mut ResponseHeader, host_config: Option<&HostConfig>) -> Result<()> {
+  match host_config.and_then(|host_config| host_config.cors.as_ref()) {
+    Some(cors) => decorate(req, resp, cors),
+    None => Ok(()),
+  }
+}
+
+/// Adds the CORS headers to a response, replacing the ones of the upstream
+pub fn decorate(req: &RequestHeader, resp: &mut ResponseHeader, cors: &CorsConfig) -> Result<()> {
+  for name in [
+    http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+    http::header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+    http::header::ACCESS_CONTROL_EXPOSE_HEADERS,
+  ] {
+    resp.remove_header(&name);
+  }
+  // The response differs by origin, also for origins that get no CORS headers
+  resp.append_header(http::header::VARY, "Origin")?;
+  let Some(origin) = header(req, "Origin").filter(|origin| cors.allows_origin(origin)) else {
+    return Ok(());
+  };
+  resp.insert_header(http::header::ACCESS_CONTROL_ALLOW_ORIGIN, cors.allow_origin_value(origin).to_string())?;
+  if cors.allow_credentials() {
+    resp.insert_header(http::header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")?;
+  }
+  if let Some(exposed_headers) = &cors.exposed_headers {
+    resp.insert_header(http::header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed_headers.join(", "))?;
+  }
+  Ok(())
+}
@@ -0,0 +1,250 @@
+use std::any::Any;
+use std::path::PathBuf;
+use async_trait::async_trait;
+use bytes::Bytes;
+use pingora::cache::key::{CacheHashKey, CacheKey, CompactCacheKey};
+use pingora::cache::storage::{HandleHit, HandleMiss, HitHandler, MissFinishType, MissHandler, PurgeType, Storage};
+use pingora::cache::trace::SpanHandle;
+use pingora::cache::CacheMeta;
+use pingora::prelude::*;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{error, info};
+use uuid::Uuid;
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+// The directory below MPROXY_CACHE_PATH that belongs to mproxy
+const CACHE_DIR_NAME: &str = "mproxy-cache";
+
+/// Cache storage keeping each object as a meta and a body file below a directory.
+/// The eviction manager does not survive restarts, so the cached objects are removed on startup
+pub struct DiskCache {
+  root: PathBuf,
+}
+
+impl DiskCache {
+  pub fn new(cache_path: PathBuf) -> Self {
+    let root = cache_path.join(CACHE_DIR_NAME);
+    if let Err(e) = std::fs::create_dir_all(&root) {
+      error!("Cannot create disk cache [{}]: {}", root.display(), e);
+    }
+    Self::clear(&root);
+    info!("Disk cache at [{}]", root.display());
+    Self { root }
+  }
+
+  /// Removes the files of a previous run, only the hash directories and their object files are touched
+  fn clear(root: &PathBuf) {
+    let Ok(entries) = std::fs::read_dir(root) else {
+      return;
+    };
+    let hash_dirs = entries.flatten().map(|entry| entry.path()).filter(|path| {
+      path.is_dir()
+        && path
+          .file_name()
+          .and_then(|name| name.to_str())
+          .is_some_and(|name| name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit()))
+    });
+    for dir in hash_dirs {
+      let Ok(files) = std::fs::read_dir(&dir) else {
+        continue;
+      };
+      for file in files.flatten().map(|entry| entry.path()) {
+        let is_object = file
+          .extension()
+          .and_then(|extension| extension.to_str())
+          .is_some_and(|extension| extension == "meta" || extension == "body" || extension.starts_with("tmp-"));
+        if is_object && file.is_file() {
+          if let Err(e) = std::fs::remove_file(&file) {
+            error!("Cannot clear disk cache file [{}]: {}", file.display(), e);
+          }
+        }
+      }
+      // Fails for directories with foreign files, those are left alone
+      let _ = std::fs::remove_dir(&dir);
+    }
+  }
+
+  /// Meta and body file of a cache key hash, spread over sub directories by the first two hex digits
+  fn paths(&self, hash: &str) -> (PathBuf, PathBuf) {
+    let dir = self.root.join(&hash[..2]);
+    (dir.join(format!("{}.meta", hash)), dir.join(format!("{}.body", hash)))
+  }
+
+  fn encode_meta(meta: &CacheMeta) -> Result<Vec<u8>> {
+    let (internal, header) = meta.serialize()?;
+    let mut encoded = Vec::with_capacity(4 + internal.len() + header.len());
+    encoded.extend_from_slice(&(internal.len() as u32).to_le_bytes());
+    encoded.extend_from_slice(&internal);
+    encoded.extend_from_slice(&header);
+    Ok(encoded)
+  }
+
+  fn decode_meta(encoded: &[u8]) -> Result<CacheMeta> {
+    let internal_len = encoded
+      .get(..4)
+      .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+      .filter(|len| 4 + len <= encoded.len())
+      .or_err(InternalError, "corrupt disk cache meta")?;
+    CacheMeta::deserialize(&encoded[4..4 + internal_len], &encoded[4 + internal_len..])
+  }
+
+  /// Writes a file next to its final path first so readers never see it half written
+  async fn write_atomic(path: &PathBuf, content: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension(format!("tmp-{}", Uuid::new_v4()));
+    tokio::fs::write(&tmp_path, content)
+      .await
+      .or_err(WriteError, "writing disk cache file")?;
+    tokio::fs::rename(&tmp_path, path)
+      .await
+      .or_err(WriteError, "renaming disk cache file")
+  }
+}
+
+#[async_trait]
+impl Storage for DiskCache {
+  async fn lookup(&'static self, key: &CacheKey, _trace: &SpanHandle) -> Result<Option<(CacheMeta, HitHandler)>> {
+    let (meta_path, body_path) = self.paths(&key.combined());
+    let encoded = match tokio::fs::read(&meta_path).await {
+      Ok(encoded) => encoded,
+      Err(_) => return Ok(None),
+    };
+    let meta = Self::decode_meta(&encoded)?;
+    let body = match File::open(&body_path).await {
+      Ok(body) => body,
+      // Purged between reading the meta and the body
+      Err(_) => return Ok(None),
+    };
+    Ok(Some((meta, Box::new(DiskHitHandler { body, done: false }))))
+  }
+
+  async fn get_miss_handler(&'static self, key: &CacheKey, meta: &CacheMeta, _trace: &SpanHandle) -> Result<MissHandler> {
+    let (meta_path, body_path) = self.paths(&key.combined());
+    if let Some(dir) = meta_path.parent() {
+      tokio::fs::create_dir_all(dir)
+        .await
+        .or_err(WriteError, "creating disk cache directory")?;
+    }
+    let tmp_body_path = body_path.with_extension(format!("tmp-{}", Uuid::new_v4()));
+    let body = File::create(&tmp_body_path)
+      .await
+      .or_err(WriteError, "creating disk cache file")?;
+    Ok(Box::new(DiskMissHandler {
+      meta: Self::encode_meta(meta)?,
+      meta_path,
+      body_path,
+      tmp_body_path,
+      body,
+      size: 0,
+    }))
+  }
+
+  async fn purge(&'static self, key: &CompactCacheKey, _purge_type: PurgeType, _trace: &SpanHandle) -> Result<bool> {
+    let (meta_path, body_path) = self.paths(&key.combined());
+    let existed = tokio::fs::remove_file(&meta_path).await.is_ok();
+    let _ = tokio::fs::remove_file(&body_path).await;
+    Ok(existed)
+  }
+
+  async fn update_meta(&'static self, key: &CacheKey, meta: &CacheMeta, _trace: &SpanHandle) -> Result<bool> {
+    let (meta_path, _) = self.paths(&key.combined());
+    if !meta_path.exists() {
+      return Ok(false);
+    }
+    Self::write_atomic(&meta_path, &Self::encode_meta(meta)?).await?;
+    Ok(true)
+  }
+
+  fn support_streaming_partial_write(&self) -> bool {
+    false
+  }
+
+  fn as_any(&self) -> &(dyn Any + Send + Sync + 'static) {
+    self
+  }
+}
+
+struct DiskHitHandler {
+  body: File,
+  done: bool,
+}
+
+#[async_trait]
+impl HandleHit for DiskHitHandler {
+  async fn read_body(&mut self) -> Result<Option<Bytes>> {
+    if self.done {
+      return Ok(None);
+    }
+    let mut buf = vec![0u8; READ_CHUNK_SIZE];
+    let read = self
+      .body
+      .read(&mut buf)
+      .await
+      .or_err(ReadError, "reading disk cache file")?;
+    if read == 0 {
+      self.done = true;
+      return Ok(None);
+    }
+    buf.truncate(read);
+    Ok(Some(Bytes::from(buf)))
+  }
+
+  async fn finish(self: Box<Self>, _storage: &'static (dyn Storage + Sync), _key: &CacheKey, _trace: &SpanHandle) -> Result<()> {
+    Ok(())
+  }
+
+  fn can_seek(&self) -> bool {
+    false
+  }
+
+  fn seek(&mut self, _start: usize, _end: Option<usize>) -> Result<()> {
+    Error::e_explain(InternalError, "disk cache does not support seeking")
+  }
+
+  fn as_any(&self) -> &(dyn Any + Send + Sync) {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut (dyn Any + Send + Sync) {
+    self
+  }
+}
+
+struct DiskMissHandler {
+  meta: Vec<u8>,
+  meta_path: PathBuf,
+  body_path: PathBuf,
+  tmp_body_path: PathBuf,
+  body: File,
+  size: usize,
+}
+
+#[async_trait]
+impl HandleMiss for DiskMissHandler {
+  async fn write_body(&mut self, data: Bytes, _eof: bool) -> Result<()> {
+    self.size += data.len();
+    self
+      .body
+      .write_all(&data)
+      .await
+      .or_err(WriteError, "writing disk cache file")
+  }
+
+  async fn finish(mut self: Box<Self>) -> Result<MissFinishType> {
+    self.body.flush().await.or_err(WriteError, "writing disk cache file")?;
+    // The body is in place before the meta, a found meta always has its body
+    tokio::fs::rename(&self.tmp_body_path, &self.body_path)
+      .await
+      .or_err(WriteError, "renaming disk cache file")?;
+    DiskCache::write_atomic(&self.meta_path, &self.meta).await?;
+    Ok(MissFinishType::Created(self.size + self.meta.len()))
+  }
+}
+
+impl Drop for DiskMissHandler {
+  fn drop(&mut self) {
+    // Aborted misses leave their temporary body behind otherwise
+    let _ = std::fs::remove_file(&self.tmp_body_path);
+  }
+}
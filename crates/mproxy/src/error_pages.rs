@@ -0,0 +1,79 @@
+use std::fs;
+use bytes::Bytes;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+use pingora::protocols::http::error_resp::gen_error_response;
+use tracing::error;
+use mproxy_common::host_config::{ErrorPage, ErrorPagesConfig, HostConfig};
+use crate::cert_store::CertStore;
+use crate::cors;
+use crate::security_headers;
+
+/// Whether the client asked for JSON rather than HTML
+fn prefers_json(req: &RequestHeader) -> bool {
+  let accept = req
+    .headers
+    .get(http::header::ACCEPT)
+    .and_then(|v| v.to_str().ok())
+    .unwrap_or("");
+  accept.contains("json") && !accept.contains("text/html")
+}
+
+/// The page of a status, the pages of the host take precedence over the global ones
+fn find_page(host_config: Option<&HostConfig>, global: Option<&ErrorPagesConfig>, status: u16, connect_error: bool) -> Option<ErrorPage> {
+  host_config
+    .and_then(|host_config| host_config.error_pages.as_ref())
+    .and_then(|pages| pages.page(status, connect_error))
+    .or_else(|| global.and_then(|pages| pages.page(status, connect_error)))
+    .cloned()
+}
+
+/// Responds with the configured error page of the status, or the pingora default without one
+pub async fn respond(session: &mut Session, cert_store: &CertStore, host_config: Option<&HostConfig>, status: u16, connect_error: bool) -> Result<()> {
+  let global = cert_store.error_pages();
+  let page = find_page(host_config, global.as_ref(), status, connect_error);
+  let variant = page
+    .as_ref()
+    .and_then(|page| page.variant(prefers_json(session.req_header())))
+    .and_then(|(path, content_type)| match fs::read(path) {
+      Ok(body) => Some((body, content_type)),
+      Err(e) => {
+        error!("Cannot read error page [{}]: {}", path, e);
+        None
+      }
+    });
+  let Some((body, content_type)) = variant else {
+    let mut response_header = gen_error_response(status);
+    security_headers::apply(&mut response_header, cert_store, host_config)?;
+    cors::apply(session.req_header(), &mut response_header, host_config)?;
+    return session.write_error_response(response_header, Bytes::new()).await;
+  };
+  let headers = [
+    (http::header::CONTENT_TYPE, content_type.to_string()),
+    (http::header::CACHE_CONTROL, "no-store".to_string()),
+  ];
+  respond_with_body(session, cert_store, host_config, status, &headers, Bytes::from(body)).await
+}
+
+/// Writes a complete response mproxy answers itself, with the security and CORS headers of the host
+pub async fn respond_with_body(
+  session: &mut Session,
+  cert_store: &CertStore,
+  host_config: Option<&HostConfig>,
+  status: u16,
+  headers: &[(http::HeaderName, String)],
+  body: Bytes,
+) -> Result<()> {
+  let mut response_header = ResponseHeader::build(status, Some(headers.len() + 1))?;
+  for (name, value) in headers {
+    response_header.insert_header(name.clone(), value.as_str())?;
+  }
+  response_header.insert_header(http::header::CONTENT_LENGTH, body.len().to_string())?;
+  security_headers::apply(&mut response_header, cert_store, host_config)?;
+  cors::apply(session.req_header(), &mut response_header, host_config)?;
+  if body.is_empty() {
+    return session.write_response_header(Box::new(response_header), true).await;
+  }
+  session.write_response_header(Box::new(response_header), false).await?;
+  session.write_response_body(Some(body), true).await
+}
@@ -0,0 +1,35 @@
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+
+// https://grpc.github.io/grpc/core/md_doc_statuscodes.html
+const GRPC_DEADLINE_EXCEEDED: u16 = 4;
+const GRPC_RESOURCE_EXHAUSTED: u16 = 8;
+const GRPC_INTERNAL: u16 = 13;
+const GRPC_UNAVAILABLE: u16 = 14;
+
+pub fn is_grpc(req: &RequestHeader) -> bool {
+  req
+    .headers
+    .get(http::header::CONTENT_TYPE)
+    .and_then(|v| v.to_str().ok())
+    .is_some_and(|content_type| content_type.starts_with("application/grpc"))
+}
+
+fn grpc_status(e: &Error) -> u16 {
+  match e.etype() {
+    ConnectTimedout | ReadTimedout | WriteTimedout | HTTPStatus(504) => GRPC_DEADLINE_EXCEEDED,
+    HTTPStatus(429) => GRPC_RESOURCE_EXHAUSTED,
+    _ if *e.esource() == ErrorSource::Upstream => GRPC_UNAVAILABLE,
+    _ => GRPC_INTERNAL,
+  }
+}
+
+/// Answers a failed gRPC call with a trailers-only response, gRPC clients expect
+/// status 200 and the error in `grpc-status` instead of an HTTP error page
+pub async fn respond_error(session: &mut Session, e: &Error) -> Result<()> {
+  let mut response_header = ResponseHeader::build(200, Some(3))?;
+  response_header.insert_header(http::header::CONTENT_TYPE, "application/grpc")?;
+  response_header.insert_header("grpc-status", grpc_status(e).to_string())?;
+  response_header.insert_header("grpc-message", e.etype().as_str())?;
+  session.write_response_header(Box::new(response_header), true).await
+}
@@ -0,0 +1,55 @@
+use pingora::http::{RequestHeader, ResponseHeader};
+use tracing::error;
+use mproxy_common::host_config::HeaderRules;
+
+/// The values of the variables usable in header rules
+pub struct HeaderVars<'a> {
+  pub client_ip: &'a str,
+  pub host: &'a str,
+  pub scheme: &'a str,
+  pub request_id: &'a str,
+}
+
+impl HeaderVars<'_> {
+  pub fn substitute(&self, value: &str) -> String {
+    value
+      .replace("$client_ip", self.client_ip)
+      .replace("$host", self.host)
+      .replace("$scheme", self.scheme)
+      .replace("$request_id", self.request_id)
+  }
+}
+
+/// Applies header rules to the request sent upstream
+pub fn apply_request_rules(req: &mut RequestHeader, rules: &HeaderRules, vars: &HeaderVars) {
+  for name in rules.remove.iter().flatten() {
+    req.remove_header(name.as_str());
+  }
+  for (name, value) in rules.set.iter().flatten() {
+    if let Err(e) = req.insert_header(name.clone(), vars.substitute(value)) {
+      error!("Cannot set request header [{}]: {}", name, e);
+    }
+  }
+  for (name, value) in rules.append.iter().flatten() {
+    if let Err(e) = req.append_header(name.clone(), vars.substitute(value)) {
+      error!("Cannot append request header [{}]: {}", name, e);
+    }
+  }
+}
+
+/// Applies header rules to the response sent downstream
+pub fn apply_response_rules(resp: &mut ResponseHeader, rules: &HeaderRules, vars: &HeaderVars) {
+  for name in rules.remove.iter().flatten() {
+    resp.remove_header(name.as_str());
+  }
+  for (name, value) in rules.set.iter().flatten() {
+    if let Err(e) = resp.insert_header(name.clone(), vars.substitute(value)) {
+      error!("Cannot set response header [{}]: {}", name, e);
+    }
+  }
+  for (name, value) in rules.append.iter().flatten() {
+    if let Err(e) = resp.append_header(name.clone(), vars.substitute(value)) {
+      error!("Cannot append response header [{}]: {}", name, e);
+    }
+  }
+}
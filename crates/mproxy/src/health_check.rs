@@ -0,0 +1,105 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use async_trait::async_trait;
+use pingora::connectors::http::Connector;
+use pingora::lb::health_check::{HealthCheck, TcpHealthCheck};
+use pingora::lb::Backend;
+use pingora::prelude::*;
+use tracing::{info, warn};
+use mproxy_common::host_config::{HealthCheckConfig, HealthCheckType};
+use crate::upstream_peer::UpstreamPeerBuilder;
+
+/// Active TCP/HTTP probe of the backends of an upstream pool
+pub struct UpstreamHealthCheck {
+  pool_key: String,
+  host: String,
+  config: HealthCheckConfig,
+  tcp_check: Box<TcpHealthCheck>,
+  connector: Connector,
+  peer_builder: Arc<UpstreamPeerBuilder>,
+}
+
+impl Debug for UpstreamHealthCheck {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "UpstreamHealthCheck [{}]", self.pool_key)
+  }
+}
+
+impl UpstreamHealthCheck {
+  pub fn new(pool_key: &str, host_name: &str, config: HealthCheckConfig, peer_builder: Arc<UpstreamPeerBuilder>) -> Box<Self> {
+    let mut tcp_check = TcpHealthCheck::new();
+    tcp_check.consecutive_success = config.rise();
+    tcp_check.consecutive_failure = config.fall();
+    tcp_check.peer_template.options.connection_timeout = Some(config.timeout());
+    Box::new(UpstreamHealthCheck {
+      pool_key: pool_key.to_string(),
+      host: config.host.clone().unwrap_or_else(|| host_name.to_string()),
+      config,
+      tcp_check,
+      connector: Connector::new(None),
+      peer_builder,
+    })
+  }
+
+  async fn check_http(&self, target: &Backend) -> Result<()> {
+    // Same peer (TLS settings included) as the proxied requests, only with the check timeouts
+    let mut peer = self.peer_builder.new_peer(target)?;
+    peer.options.connection_timeout = Some(self.config.timeout());
+    peer.options.read_timeout = Some(self.config.timeout());
+
+    let (mut session, _) = self.connector.get_http_session(&peer).await?;
+    let mut req = RequestHeader::build("GET", self.config.path.as_deref().unwrap_or("/").as_bytes(), None)?;
+    req.insert_header("Host", self.host.as_str())?;
+    req.insert_header("User-Agent", "mproxy-health-check")?;
+    req.insert_header("Connection", "close")?;
+    session.write_request_header(Box::new(req)).await?;
+    session.finish_request_body().await?;
+    session.read_response_header().await?;
+
+    let status = session.response_header().map_or(0, |resp| resp.status.as_u16());
+    let expected_status = self.config.expected_status.unwrap_or(200);
+    if status != expected_status {
+      return Error::e_explain(CustomCode("unexpected status", status), "during http health check");
+    }
+
+    let mut body: Vec<u8> = Vec::new();
+    while let Some(chunk) = session.read_response_body().await? {
+      body.extend_from_slice(&chunk);
+    }
+    if let Some(expected_body) = &self.config.expected_body {
+      if !String::from_utf8_lossy(&body).contains(expected_body.as_str()) {
+        return Error::e_explain(Custom("unexpected body"), "during http health check");
+      }
+    }
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl HealthCheck for UpstreamHealthCheck {
+  async fn check(&self, target: &Backend) -> Result<()> {
+    match self.config.check_type {
+      HealthCheckType::Tcp => self.tcp_check.check(target).await,
+      HealthCheckType::Http => match tokio::time::timeout(self.config.timeout(), self.check_http(target)).await {
+        Ok(result) => result,
+        Err(_) => Error::e_explain(ReadTimedout, "during http health check"),
+      },
+    }
+  }
+
+  async fn health_status_change(&self, target: &Backend, healthy: bool) {
+    if healthy {
+      info!("Upstream [{}] of [{}] is healthy again", target.addr, self.pool_key);
+    } else {
+      warn!("Upstream [{}] of [{}] is unhealthy - removed from rotation", target.addr, self.pool_key);
+    }
+  }
+
+  fn health_threshold(&self, success: bool) -> usize {
+    if success {
+      self.config.rise()
+    } else {
+      self.config.fall()
+    }
+  }
+}
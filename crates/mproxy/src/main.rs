@@ -15,6 +15,22 @@ use crate::cert_store::CertStore;
 mod server;
 mod cert_store;
 mod cert_handler;
+mod upstream_pool;
+mod upstream_peer;
+mod health_check;
+mod outlier;
+mod static_files;
+mod headers;
+mod basic_auth;
+mod rate_limit;
+mod grpc;
+mod cache;
+mod disk_cache;
+mod admin_api;
+mod error_pages;
+mod cors;
+mod security_headers;
+mod maintenance;
 // mod s3_proxy;
 
 #[tokio::main]
@@ -30,7 +46,7 @@ async fn main() {
     // try to ensure challenge path
     let challenge_path = acme_challenge_path();
     if !PathBuf::from(&challenge_path).exists() {
-        fs::create_dir_all(&challenge_path).expect(format!("Failed to create challenge path at: [{}]",challenge_path).as_str());
+        fs::create_dir_all(&challenge_path).unwrap_or_else(|_| panic!("Failed to create challenge path at: [{}]", challenge_path));
     }
 
     let mut join_handles: Vec<JoinHandle<()>> =  Vec::new();
@@ -43,18 +59,45 @@ async fn main() {
     let mut cert_store = CertStore::new();
 
     cert_store.load_certs_from_host_config_list(&config_loader.load());
+    upstream_pool::sync_pools(&config).await;
+    basic_auth::sync_files(&config).await;
+    cache::init(&config);
     cert_store.set_host_config_loader(config_loader);
 
     let monitor_handle = tokio::spawn(async move {
         loop {
             // info!("Monitoring...");
             tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
-            cert_store.refresh_hosts();
+            if let Some(config) = cert_store.refresh_hosts() {
+                // Requests only find new hosts and locations once their pools exist
+                upstream_pool::sync_pools(&config).await;
+                basic_auth::sync_files(&config).await;
+                cache::init(&config);
+                cert_store.load_certs_from_host_config_list(&config);
+            }
         }
     });
 
     join_handles.push(monitor_handle);
 
+    let health_check_handle = tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            upstream_pool::run_health_checks().await;
+        }
+    });
+
+    join_handles.push(health_check_handle);
+
+    let htpasswd_handle = tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+            basic_auth::reload_changed_files().await;
+        }
+    });
+
+    join_handles.push(htpasswd_handle);
+
     std::thread::spawn(move || {
         server::server::start_server();
     });
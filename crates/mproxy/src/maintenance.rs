@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// Whether the maintenance marker files existed when they were last checked
+static MAINTENANCE_MARKERS: LazyLock<Mutex<HashMap<PathBuf, (Instant, bool)>>> = LazyLock::new(|| {
+  info!("MAINTENANCE_MARKERS Init");
+  Mutex::new(HashMap::new())
+});
+
+// How long a check of a marker file is reused, creating or removing it takes effect within this time
+const MARKER_TTL: Duration = Duration::from_secs(2);
+
+/// Whether the maintenance marker file exists, the file system is checked at most once per `MARKER_TTL`
+pub fn marker_exists(path: &Path) -> bool {
+  marker_exists_at(path, Instant::now())
+}
+
+fn marker_exists_at(path: &Path, now: Instant) -> bool {
+  if let Some((checked, exists)) = MAINTENANCE_MARKERS.lock().unwrap().get(path) {
+    if now.saturating_duration_since(*checked) < MARKER_TTL {
+      return *exists;
+    }
+  }
+  // Checked without holding the lock, concurrent requests may check the file at the same time
+  let exists = fs::exists(path).unwrap_or(false);
+  MAINTENANCE_MARKERS.lock().unwrap().insert(path.to_path_buf(), (now, exists));
+  exists
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use uuid::Uuid;
+
+  #[test]
+  fn reuses_the_check_of_a_marker_until_it_expires() {
+    let path = std::env::temp_dir().join(format!("mproxy-maintenance-{}", Uuid::new_v4()));
+    let start = Instant::now();
+    assert!(!marker_exists_at(&path, start));
+    fs::write(&path, "").unwrap();
+    assert!(!marker_exists_at(&path, start + Duration::from_secs(1)));
+    assert!(marker_exists_at(&path, start + MARKER_TTL));
+    fs::remove_file(&path).unwrap();
+    assert!(marker_exists_at(&path, start + MARKER_TTL));
+    assert!(!marker_exists_at(&path, start + MARKER_TTL * 2));
+  }
+}
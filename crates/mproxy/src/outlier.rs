@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use pingora::lb::Backend;
+use tracing::{info, warn};
+use crate::upstream_pool::backend_key;
+use mproxy_common::host_config::OutlierDetectionConfig;
+
+#[derive(Debug, Default)]
+struct BackendState {
+  consecutive_failures: usize,
+  // Ejections in a row, used for the exponential backoff
+  ejections: u32,
+  ejected_until: Option<Instant>,
+  // Trial requests in flight once the ejection time is over
+  probes_in_flight: usize,
+}
+
+impl BackendState {
+  fn is_ejected(&self) -> bool {
+    self.ejected_until.is_some_and(|until| until > Instant::now())
+  }
+
+  fn is_probing(&self) -> bool {
+    self.ejected_until.is_some() && !self.is_ejected()
+  }
+}
+
+/// Passive failure tracking of the backends of an upstream pool
+#[derive(Debug)]
+pub struct OutlierDetector {
+  pool_key: String,
+  config: OutlierDetectionConfig,
+  states: Mutex<HashMap<u64, BackendState>>,
+}
+
+impl OutlierDetector {
+  pub fn new(pool_key: &str, config: OutlierDetectionConfig) -> Self {
+    Self {
+      pool_key: pool_key.to_string(),
+      config,
+      states: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Returns true when the backend may receive a request, without reserving a trial request
+  pub fn is_available(&self, backend: &Backend) -> bool {
+    let states = self.states.lock().unwrap();
+    match states.get(&backend_key(backend)) {
+      Some(state) if state.is_ejected() => false,
+      Some(state) if state.is_probing() => state.probes_in_flight < self.config.probe_requests(),
+      _ => true,
+    }
+  }
+
+  /// Returns true when the backend may receive a request, reserving a trial request if it is on probation
+  pub fn admit(&self, backend: &Backend) -> bool {
+    let mut states = self.states.lock().unwrap();
+    let Some(state) = states.get_mut(&backend_key(backend)) else {
+      return true;
+    };
+    if state.is_ejected() {
+      return false;
+    }
+    if state.is_probing() {
+      if state.probes_in_flight >= self.config.probe_requests() {
+        return false;
+      }
+      state.probes_in_flight += 1;
+    }
+    true
+  }
+
+  pub fn report_success(&self, backend: &Backend) {
+    let mut states = self.states.lock().unwrap();
+    if let Some(state) = states.get_mut(&backend_key(backend)) {
+      if state.is_probing() {
+        info!("Upstream [{}] of [{}] passed its trial request - back in rotation", backend.addr, self.pool_key);
+      }
+      *state = BackendState::default();
+    }
+  }
+
+  pub fn report_failure(&self, backend: &Backend, reason: &str) {
+    let mut states = self.states.lock().unwrap();
+    let state = states.entry(backend_key(backend)).or_default();
+    if state.is_ejected() {
+      return;
+    }
+    state.consecutive_failures += 1;
+    // A failed trial request ejects again right away
+    if state.is_probing() || state.consecutive_failures >= self.config.consecutive_failures() {
+      let ejection_time = self.config.ejection_time(state.ejections);
+      warn!(
+        "Upstream [{}] of [{}] ejected for {}s after {} consecutive failures (last: {})",
+        backend.addr,
+        self.pool_key,
+        ejection_time.as_secs(),
+        state.consecutive_failures,
+        reason
+      );
+      state.ejections = state.ejections.saturating_add(1);
+      state.ejected_until = Some(Instant::now() + ejection_time);
+      state.consecutive_failures = 0;
+      state.probes_in_flight = 0;
+    }
+  }
+
+  /// Gives back a trial request that ended without a verdict (e.g. the client went away)
+  pub fn release_probe(&self, backend: &Backend) {
+    let mut states = self.states.lock().unwrap();
+    if let Some(state) = states.get_mut(&backend_key(backend)) {
+      state.probes_in_flight = state.probes_in_flight.saturating_sub(1);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn detector(ejection_secs: u64) -> OutlierDetector {
+    OutlierDetector::new(
+      "test",
+      OutlierDetectionConfig {
+        consecutive_failures: Some(2),
+        ejection_secs: Some(ejection_secs),
+        max_ejection_secs: None,
+        probe_requests: Some(1),
+      },
+    )
+  }
+
+  #[test]
+  fn ejects_after_consecutive_failures() {
+    let detector = detector(30);
+    let backend = Backend::new("127.0.0.1:8080").unwrap();
+    detector.report_failure(&backend, "connect");
+    // A success in between resets the count
+    detector.report_success(&backend);
+    detector.report_failure(&backend, "connect");
+    assert!(detector.admit(&backend));
+    detector.report_failure(&backend, "502");
+    assert!(!detector.is_available(&backend));
+    assert!(!detector.admit(&backend));
+    // Other backends are not affected
+    assert!(detector.admit(&Backend::new("127.0.0.1:8081").unwrap()));
+  }
+
+  #[test]
+  fn limits_trial_requests_after_the_ejection() {
+    // Without ejection time the backend is on probation right away
+    let detector = detector(0);
+    let backend = Backend::new("127.0.0.1:8080").unwrap();
+    detector.report_failure(&backend, "connect");
+    detector.report_failure(&backend, "connect");
+    assert!(detector.admit(&backend));
+    assert!(!detector.is_available(&backend));
+    assert!(!detector.admit(&backend));
+    detector.release_probe(&backend);
+    assert!(detector.admit(&backend));
+
+    // A failed trial request ejects again without waiting for the consecutive failures
+    detector.report_failure(&backend, "502");
+    assert_eq!(detector.states.lock().unwrap()[&backend_key(&backend)].ejections, 2);
+    assert!(detector.admit(&backend));
+    detector.report_success(&backend);
+    assert!(detector.admit(&backend));
+    assert!(detector.admit(&backend));
+  }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use pingora::http::RequestHeader;
+use tracing::{info, warn};
+use mproxy_common::host_config::{RateLimitConfig, RateLimitKey};
+
+// Token buckets by rate limit scope and limit
+static RATE_LIMITERS: LazyLock<Mutex<HashMap<String, Arc<Limiter>>>> = LazyLock::new(|| {
+  info!("RATE_LIMITERS Init");
+  Mutex::new(HashMap::new())
+});
+
+// How many buckets a limiter holds before the full ones are dropped
+const MIN_PRUNE_SIZE: usize = 1024;
+
+/// The tokens of a single key
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+struct Buckets {
+  by_key: HashMap<String, Bucket>,
+  /// Size at which the full buckets are dropped, they behave like a new bucket
+  prune_at: usize,
+}
+
+/// Token bucket limiter: each key may burst up to `capacity` requests,
+/// the tokens are refilled evenly over the period
+struct Limiter {
+  capacity: f64,
+  refill_per_sec: f64,
+  buckets: Mutex<Buckets>,
+}
+
+impl Limiter {
+  fn new(requests: u32, period: Duration) -> Self {
+    let capacity = f64::from(requests);
+    Self {
+      capacity,
+      refill_per_sec: capacity / period.as_secs_f64(),
+      buckets: Mutex::new(Buckets {
+        by_key: HashMap::new(),
+        prune_at: MIN_PRUNE_SIZE,
+      }),
+    }
+  }
+
+  fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+    (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity)
+  }
+
+  /// Takes a token of the key, or returns how long it takes until the next token is available
+  fn acquire(&self, key: &str, now: Instant) -> Result<(), Duration> {
+    let mut buckets = self.buckets.lock().unwrap();
+    if buckets.by_key.len() >= buckets.prune_at {
+      buckets.by_key.retain(|_, bucket| self.refilled(bucket, now) < self.capacity);
+      buckets.prune_at = (buckets.by_key.len() * 2).max(MIN_PRUNE_SIZE);
+    }
+    let bucket = buckets.by_key.entry(key.to_string()).or_insert(Bucket {
+      tokens: self.capacity,
+      updated: now,
+    });
+    bucket.tokens = self.refilled(bucket, now);
+    bucket.updated = now;
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      return Ok(());
+    }
+    Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_per_sec))
+  }
+}
+
+fn limiter(scope: &str, config: &RateLimitConfig) -> Arc<Limiter> {
+  // The limit is part of the key, a changed limit starts with full buckets
+  let key = format!("{}|{}|{}", scope, config.requests(), config.period().as_secs());
+  RATE_LIMITERS
+    .lock()
+    .unwrap()
+    .entry(key)
+    .or_insert_with(|| Arc::new(Limiter::new(config.requests(), config.period())))
+    .clone()
+}
+
+fn header_value(req: &RequestHeader, name: &str) -> Option<String> {
+  req
+    .headers
+    .get(name)
+    .and_then(|v| v.to_str().ok())
+    .filter(|v| !v.is_empty())
+    .map(str::to_string)
+}
+
+/// The value requests are counted by
+fn request_key(req: &RequestHeader, config: &RateLimitConfig, client_ip: &str) -> String {
+  let key = match config.key {
+    RateLimitKey::ClientIp => None,
+    RateLimitKey::Header => config.header.as_deref().and_then(|header| header_value(req, header)),
+    RateLimitKey::ApiKey => header_value(req, "X-API-Key").or_else(|| {
+      header_value(req, "Authorization").and_then(|auth| auth.strip_prefix("Bearer ").map(|token| token.trim().to_string()))
+    }),
+  };
+  key.unwrap_or_else(|| client_ip.to_string())
+}
+
+/// Counts the request and returns the seconds to wait before retrying when the limit is exceeded
+pub fn check(scope: &str, config: &RateLimitConfig, req: &RequestHeader, client_ip: &str) -> Option<u64> {
+  let key = request_key(req, config, client_ip);
+  let wait = limiter(scope, config).acquire(&key, Instant::now()).err()?;
+  // API keys are not written to the log, only the client IP
+  warn!(
+    "Rate limit exceeded on [{}] for [{}] by {:?}: limit [{}] requests in [{}s]",
+    scope,
+    client_ip,
+    config.key,
+    config.requests(),
+    config.period().as_secs()
+  );
+  Some(wait.as_secs_f64().ceil().max(1.0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bursts_up_to_the_limit() {
+    let limiter = Limiter::new(3, Duration::from_secs(60));
+    let now = Instant::now();
+    for _ in 0..3 {
+      assert!(limiter.acquire("a", now).is_ok());
+    }
+    assert!(limiter.acquire("a", now).is_err());
+    // Keys have their own buckets
+    assert!(limiter.acquire("b", now).is_ok());
+  }
+
+  #[test]
+  fn refills_evenly_over_the_period() {
+    let limiter = Limiter::new(2, Duration::from_secs(60));
+    let start = Instant::now();
+    assert!(limiter.acquire("a", start).is_ok());
+    assert!(limiter.acquire("a", start).is_ok());
+    // One token every 30 seconds, not the whole limit at the start of a new window
+    let wait = limiter.acquire("a", start + Duration::from_secs(10)).unwrap_err();
+    assert!((wait.as_secs_f64() - 20.0).abs() < 0.001);
+    assert!(limiter.acquire("a", start + Duration::from_secs(30)).is_ok());
+    assert!(limiter.acquire("a", start + Duration::from_secs(30)).is_err());
+    // Idle time refills no more than the limit
+    let later = start + Duration::from_secs(3600);
+    assert!(limiter.acquire("a", later).is_ok());
+    assert!(limiter.acquire("a", later).is_ok());
+    assert!(limiter.acquire("a", later).is_err());
+  }
+
+  #[test]
+  fn drops_full_buckets_when_growing() {
+    let limiter = Limiter::new(1, Duration::from_secs(1));
+    let start = Instant::now();
+    for i in 0..MIN_PRUNE_SIZE {
+      assert!(limiter.acquire(&i.to_string(), start).is_ok());
+    }
+    assert!(limiter.acquire("last", start + Duration::from_secs(2)).is_ok());
+    assert_eq!(limiter.buckets.lock().unwrap().by_key.len(), 1);
+  }
+}
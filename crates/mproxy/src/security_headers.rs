@@ -0,0 +1,14 @@
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+use mproxy_common::host_config::HostConfig;
+use crate::cert_store::CertStore;
+
+/// Adds the security headers of a host to a response, headers already set (e.g. by the upstream) win
+pub fn apply(response_header: &mut ResponseHeader, cert_store: &CertStore, host_config: Option<&HostConfig>) -> Result<()> {
+  for (name, value) in cert_store.security_headers(host_config).headers() {
+    if !response_header.headers.contains_key(name) {
+      response_header.insert_header(name, value)?;
+    }
+  }
+  Ok(())
+}
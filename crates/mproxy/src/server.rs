@@ -1,25 +1,43 @@
 pub mod server {
     use async_trait::async_trait;
     use mproxy_common::{acme_challenge_path};
-    use pingora::http::{ResponseHeader, StatusCode};
+    use mproxy_common::host_config::{normalize_request_path, CacheConfig, HostConfig, Location, MaintenanceConfig, UnknownHostAction};
+    use pingora::http::{RequestHeader, ResponseHeader, StatusCode};
     use pingora::listeners::tls::TlsSettings;
     use pingora::listeners::{TcpSocketOptions, ALPN};
-    use pingora::modules::http::compression::ResponseCompressionBuilder;
+    use pingora::modules::http::compression::{ResponseCompression, ResponseCompressionBuilder};
     use pingora::modules::http::HttpModules;
     use pingora::prelude::*;
+    use pingora::proxy::FailToProxy;
+    use pingora::cache::{CacheKey, CacheMeta, NoCacheReason, RespCacheable};
+    use pingora::cache::key::HashBinary;
     use pingora::protocols::TcpKeepalive;
     use pingora::server::configuration::ServerConf;
     use pingora::server::RunArgs;
-    use pingora::upstreams::peer::PeerOptions;
     use pingora::ErrorSource::Upstream;
     use std::fmt::{Debug};
     use std::fs;
     use std::path::PathBuf;
     use std::time::Duration;
-    use tracing::{error, info};
+    use tracing::{error, info, warn};
     use bytes::Bytes;
+    use uuid::Uuid;
     use crate::cert_handler::CertHandler;
     use crate::cert_store::CertStore;
+    use crate::upstream_pool::{pool_for, SelectedUpstream};
+    use crate::static_files;
+    use crate::basic_auth;
+    use crate::rate_limit;
+    use crate::grpc;
+    use crate::cache;
+    use crate::error_pages::{self, respond_with_body};
+    use crate::cors;
+    use crate::maintenance;
+    use crate::security_headers;
+    use crate::admin_api::AdminApi;
+    use pingora::apps::http_app::HttpServer;
+    use pingora::services::listening::Service;
+    use crate::headers::{apply_request_rules, apply_response_rules, HeaderVars};
 
     #[derive(Clone, Debug)]
     pub struct TlsProxyApp {}
@@ -29,64 +47,286 @@ pub mod server {
         server_name: Option<String>,
         cert_store: CertStore,
         client_ip: String,
+        host_config: Option<HostConfig>,
+        /// The location rule that matched the request path, if any
+        location: Option<Location>,
+        /// The upstream backend currently serving the request
+        upstream: Option<SelectedUpstream>,
+        /// How many upstream peers were requested, retries included
+        attempts: usize,
+        /// The cache settings when the response of the request may be cached
+        cache: Option<CacheConfig>,
+        request_id: String,
     }
 
-    #[async_trait]
-    impl ProxyHttp for TlsProxyApp {
-        type CTX = HttpCtx;
-
-        fn new_ctx(&self) -> Self::CTX {
+    impl HttpCtx {
+        fn new() -> Self {
             HttpCtx {
                 server_name: None,
                 cert_store: CertStore::new(),
                 client_ip: String::new(),
+                host_config: None,
+                location: None,
+                upstream: None,
+                attempts: 0,
+                cache: None,
+                request_id: String::new(),
+            }
+        }
+
+        fn header_vars(&self) -> HeaderVars<'_> {
+            HeaderVars {
+                client_ip: &self.client_ip,
+                host: self.server_name.as_deref().unwrap_or(""),
+                scheme: "https",
+                request_id: &self.request_id,
             }
         }
 
+        fn release_upstream(&mut self) {
+            if let Some(upstream) = self.upstream.take() {
+                upstream.release();
+            }
+        }
+    }
+
+    fn upstream_error(status: u16, context: &'static str) -> Box<Error> {
+        Box::new(Error {
+            etype: HTTPStatus(status),
+            esource: Upstream,
+            retry: RetryType::Decided(false),
+            cause: None,
+            context: Option::from(ImmutStr::from(context)),
+        })
+    }
+
+    /// The status of the error response, 0 when the client connection is already gone (like the pingora default)
+    fn error_status(e: &Error) -> u16 {
+        match e.etype() {
+            HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    WriteError | ReadError | ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        }
+    }
+
+    /// The value of a request cookie, clients may send several Cookie headers
+    fn request_cookie(req: &RequestHeader, name: &str) -> Option<String> {
+        req.headers
+            .get_all(http::header::COOKIE)
+            .iter()
+            .filter_map(|cookie| cookie.to_str().ok())
+            .flat_map(|cookie| cookie.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(cookie_name, _)| *cookie_name == name)
+            .map(|(_, value)| value.to_string())
+    }
+
+    const MAINTENANCE_PAGE: &str = "<!DOCTYPE html><html><head><title>Maintenance</title></head><body><h1>Down for maintenance</h1><p>Please try again later.</p></body></html>";
+
+    async fn respond_maintenance(session: &mut Session, cert_store: &CertStore, host_config: &HostConfig, maintenance: &MaintenanceConfig) -> Result<()> {
+        let page = match &maintenance.page {
+            Some(page) => fs::read(page).unwrap_or_else(|e| {
+                error!("Cannot read maintenance page [{}]: {}", page, e);
+                MAINTENANCE_PAGE.as_bytes().to_vec()
+            }),
+            None => MAINTENANCE_PAGE.as_bytes().to_vec(),
+        };
+        let headers = [
+            (http::header::CONTENT_TYPE, "text/html; charset=utf-8".to_string()),
+            (http::header::RETRY_AFTER, maintenance.retry_after_secs().to_string()),
+            (http::header::CACHE_CONTROL, "no-store".to_string()),
+        ];
+        respond_with_body(session, cert_store, Some(host_config), 503, &headers, Bytes::from(page)).await
+    }
+
+    async fn respond_redirect(session: &mut Session, cert_store: &CertStore, host_config: &HostConfig, status: u16, location: String) -> Result<()> {
+        // HSTS preload requires the header on the redirect of the bare domain too
+        respond_with_body(session, cert_store, Some(host_config), status, &[(http::header::LOCATION, location)], Bytes::new()).await
+    }
+
+    #[async_trait]
+    impl ProxyHttp for TlsProxyApp {
+        type CTX = HttpCtx;
+
+        fn new_ctx(&self) -> Self::CTX {
+            HttpCtx::new()
+        }
+
         async fn upstream_peer(
             &self,
             session: &mut Session,
             ctx: &mut Self::CTX,
         ) -> Result<Box<HttpPeer>> {
             // find peer address
-            match ctx.cert_store.get_cert(ctx.server_name.as_ref().unwrap()) {
+            match &ctx.host_config {
                 None => {
+                    // The error response is written by fail_to_proxy
                     error!("No cert found for: {}", ctx.server_name.as_ref().unwrap());
-                    if let Err(e) = session.respond_error(502).await {
-                        error!("Error responding to client: {}", e);
-                    }
-                    Err(Box::new(Error {
-                        etype: HTTPStatus(502),
-                        esource: Upstream,
-                        retry: RetryType::Decided(false),
-                        cause: None,
-                        context: Option::from(ImmutStr::from("Invalid Host Requested")),
-                    }))
+                    Err(upstream_error(502, "Invalid Host Requested"))
+                }
+                Some(host_config) => {
+                    let Some(pool) = pool_for(host_config, ctx.location.as_ref()) else {
+                        error!("No upstream pool for: [{}]", host_config.pool_key(ctx.location.as_ref()));
+                        return Err(upstream_error(502, "No upstream available"));
+                    };
+                    // Requests without the hash header would all land on the same backend
+                    let hash_key = pool
+                        .hash_header()
+                        .and_then(|header| session.req_header().headers.get(header))
+                        .map_or_else(|| ctx.client_ip.as_bytes().to_vec(), |v| v.as_bytes().to_vec());
+                    // A retried request gives back the backend of the failed attempt first
+                    ctx.release_upstream();
+                    ctx.attempts += 1;
+                    // Retries go through the balancer, the affinity cookie points at the failed backend
+                    let sticky = pool
+                        .sticky()
+                        .filter(|_| ctx.attempts == 1)
+                        .and_then(|sticky| request_cookie(session.req_header(), sticky.cookie_name()))
+                        .and_then(|affinity| pool.select_sticky(&affinity));
+                    let selected = match sticky.or_else(|| pool.select(&hash_key)) {
+                        Some(selected) => selected,
+                        None => {
+                            error!("No healthy upstream left for: [{}]", pool.key());
+                            return Err(upstream_error(503, "No healthy upstream available"));
+                        }
+                    };
+                    let peer = pool.new_peer(&selected.backend);
+                    ctx.upstream = Some(selected);
+                    Ok(Box::new(peer?))
+                }
+            }
+        }
+
+        fn fail_to_connect(&self, _session: &mut Session, _peer: &HttpPeer, ctx: &mut Self::CTX, mut e: Box<Error>) -> Box<Error> {
+            if let Some(upstream) = ctx.upstream.as_mut() {
+                upstream.report_failure(e.etype().as_str());
+                // Nothing was sent yet, so any request can be retried on (possibly) another backend
+                if let Some(retries) = upstream.pool.retries() {
+                    e.set_retry(ctx.attempts <= retries);
                 }
-                Some(cert) => {
-                    let mut peer = HttpPeer::new(
-                        cert.host_config.unwrap().upstream_address,
-                        false,
-                        String::new(),
+            }
+            e
+        }
+
+        fn error_while_proxy(&self, peer: &HttpPeer, session: &mut Session, e: Box<Error>, ctx: &mut Self::CTX, client_reused: bool) -> Box<Error> {
+            // Only errors caused by the upstream count against it, not clients going away
+            if *e.esource() == Upstream {
+                if let Some(upstream) = ctx.upstream.as_mut() {
+                    upstream.report_failure(e.etype().as_str());
+                }
+            }
+            let mut e = e.more_context(format!("Peer: {}", peer));
+            e.retry.decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+            if let Some(retries) = ctx.upstream.as_ref().and_then(|upstream| upstream.pool.retries()) {
+                if ctx.attempts > retries {
+                    e.set_retry(false);
+                } else if session.req_header().method.is_idempotent()
+                    && session.response_written().is_none()
+                    && !session.as_ref().retry_buffer_truncated()
+                {
+                    e.set_retry(true);
+                }
+            }
+            e
+        }
+
+        fn request_cache_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<()> {
+            let method = &session.req_header().method;
+            if method != http::Method::GET && method != http::Method::HEAD {
+                return Ok(());
+            }
+            if let Some(config) = ctx.host_config.as_ref().and_then(|host_config| host_config.cache_for(ctx.location.as_ref())) {
+                cache::enable(session, config);
+                ctx.cache = Some(config.clone());
+            }
+            Ok(())
+        }
+
+        fn cache_key_callback(&self, session: &Session, ctx: &mut Self::CTX) -> Result<CacheKey> {
+            Ok(cache::cache_key(session.req_header(), ctx.server_name.as_deref().unwrap_or("")))
+        }
+
+        fn response_cache_filter(&self, session: &Session, resp: &ResponseHeader, ctx: &mut Self::CTX) -> Result<RespCacheable> {
+            match &ctx.cache {
+                Some(config) => Ok(cache::response_cacheable(session.req_header(), resp, config)),
+                None => Ok(RespCacheable::Uncacheable(NoCacheReason::Custom("cache disabled"))),
+            }
+        }
+
+        fn should_serve_stale(&self, _session: &mut Session, ctx: &mut Self::CTX, error: Option<&Error>) -> bool {
+            ctx.cache.as_ref().is_some_and(|config| cache::should_serve_stale(config, error))
+        }
+
+        fn cache_vary_filter(&self, meta: &CacheMeta, _ctx: &mut Self::CTX, req: &RequestHeader) -> Option<HashBinary> {
+            cache::variance(meta, req)
+        }
+
+        async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> FailToProxy
+        where
+            Self::CTX: Send + Sync,
+        {
+            let code = error_status(e);
+            if code > 0 && session.response_written().is_none() {
+                let result = if grpc::is_grpc(session.req_header()) {
+                    grpc::respond_error(session, e).await
+                } else {
+                    let connect_error = matches!(
+                        e.etype(),
+                        ConnectTimedout | ConnectRefused | ConnectNoRoute | ConnectError | TLSHandshakeFailure | TLSHandshakeTimedout
                     );
-                    let mut peer_options = PeerOptions::new();
-                    peer_options.idle_timeout = Some(Duration::from_secs(120));
-                    peer_options.tcp_fast_open = true;
-                    peer_options.alpn = Some(ALPN::H1).unwrap();
-                    peer_options.max_h2_streams = 16;
-                    peer_options.tcp_keepalive = Some(TcpKeepalive {
-                        count: 32,
-                        idle: Duration::from_secs(60),
-                        interval: Duration::from_secs(30),
-                        #[cfg(target_os = "linux")]
-                        user_timeout: Duration::from_secs(0),
-                    });
-                    peer_options.extra_proxy_headers.insert("X-Forwarded-Proto".to_string(), "https".as_bytes().to_vec());
-                    peer_options.extra_proxy_headers.insert("X-Forwarded-For".to_string(), peer._address.to_string().as_bytes().to_vec());
-                    peer.options = peer_options;
-                    Ok(Box::new(peer))
+                    error_pages::respond(session, &ctx.cert_store, ctx.host_config.as_ref(), code, connect_error).await
+                };
+                if let Err(e) = result {
+                    error!("Error responding to client: {}", e);
+                }
+            }
+            FailToProxy {
+                error_code: code,
+                can_reuse_downstream: false,
+            }
+        }
+
+        async fn response_filter(&self, _session: &mut Session, upstream_response: &mut ResponseHeader, ctx: &mut Self::CTX) -> Result<()>
+        where
+            Self::CTX: Send + Sync,
+        {
+            if let Some(upstream) = ctx.upstream.as_mut() {
+                if upstream_response.status.is_server_error() {
+                    upstream.report_failure(upstream_response.status.as_str());
+                } else {
+                    upstream.report_success();
+                }
+                // (Re)pin the client when it has no or a stale affinity cookie
+                if let Some(sticky) = upstream.pool.sticky() {
+                    let affinity = upstream.affinity();
+                    if request_cookie(_session.req_header(), sticky.cookie_name()).as_deref() != Some(affinity.as_str()) {
+                        upstream_response.append_header(http::header::SET_COOKIE, sticky.set_cookie(&affinity))?;
+                    }
+                }
+            }
+            if let Some(cors_config) = ctx.host_config.as_ref().and_then(|host_config| host_config.cors.as_ref()) {
+                cors::decorate(_session.req_header(), upstream_response, cors_config)?;
+            }
+            if let Some(config) = &ctx.cache {
+                cache::index_response(_session, config, ctx.server_name.as_deref().unwrap_or(""));
+                upstream_response.insert_header("X-Cache", cache::status(_session))?;
+            }
+            // The header rules of the host can still change them
+            security_headers::apply(upstream_response, &ctx.cert_store, ctx.host_config.as_ref())?;
+            if let Some(host_config) = &ctx.host_config {
+                for headers in host_config.headers_for(ctx.location.as_ref()) {
+                    if let Some(rules) = &headers.response {
+                        apply_response_rules(upstream_response, rules, &ctx.header_vars());
+                    }
                 }
             }
+            Ok(())
         }
 
         async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool>
@@ -96,9 +336,96 @@ pub mod server {
             session.set_keepalive(Some(120));
             if ctx.server_name.is_none() {
                 error!("No host specified!");
-                let _ = session.respond_error(502).await;
+                error_pages::respond(session, &ctx.cert_store, None, 502, false).await?;
                 return Ok(true);
             }
+            // Resolve the host config and the matching location once per request
+            let server_name = ctx.server_name.clone().unwrap();
+            match ctx.cert_store.get_cert(&server_name) {
+                Some(cert) => ctx.host_config = cert.host_config,
+                None => {
+                    if let Some(unknown_host) = ctx.cert_store.unknown_host_config() {
+                        match unknown_host.action {
+                            UnknownHostAction::Close => {
+                                info!("Closing connection for unknown host: [{}]", server_name);
+                                session.set_keepalive(None);
+                                return Ok(true);
+                            }
+                            UnknownHostAction::Status => {
+                                let page = unknown_host.page.as_ref().and_then(|page| match fs::read(page) {
+                                    Ok(page) => Some(page),
+                                    Err(e) => {
+                                        error!("Cannot read unknown host page [{}]: {}", page, e);
+                                        None
+                                    }
+                                });
+                                match page {
+                                    Some(page) => respond_with_body(session, &ctx.cert_store, None, unknown_host.status(), &[(http::header::CONTENT_TYPE, "text/html; charset=utf-8".to_string())], Bytes::from(page)).await?,
+                                    None => error_pages::respond(session, &ctx.cert_store, None, unknown_host.status(), false).await?,
+                                }
+                                return Ok(true);
+                            }
+                            UnknownHostAction::Fallback => {
+                                ctx.host_config = ctx.cert_store.get_default_cert().and_then(|cert| cert.host_config);
+                            }
+                        }
+                    }
+                }
+            }
+            if let Some(host_config) = &ctx.host_config {
+                if !host_config.is_access_allowed(&ctx.client_ip) {
+                    info!("Access denied for [{}] to [{}]", ctx.client_ip, server_name);
+                    error_pages::respond(session, &ctx.cert_store, Some(host_config), 403, false).await?;
+                    return Ok(true);
+                }
+                if let Some(maintenance) = host_config.active_maintenance(maintenance::marker_exists) {
+                    if !maintenance.is_allowed(&ctx.client_ip) {
+                        respond_maintenance(session, &ctx.cert_store, host_config, &maintenance).await?;
+                        return Ok(true);
+                    }
+                }
+                // Preflight requests carry no credentials, they are answered before basic auth
+                if let Some(cors_config) = host_config.cors.as_ref().filter(|_| cors::is_preflight(session.req_header())) {
+                    cors::respond_preflight(session, &ctx.cert_store, host_config, cors_config).await?;
+                    return Ok(true);
+                }
+                let uri = &session.req_header().uri;
+                if let Some(redirect) = &host_config.redirect {
+                    let location = redirect.location(uri.path(), uri.query());
+                    respond_redirect(session, &ctx.cert_store, host_config, redirect.status(), location).await?;
+                    return Ok(true);
+                }
+                if host_config.redirects_alias(&server_name) {
+                    let location = format!("https://{}{}", host_config.host_name, uri.path_and_query().map_or("/", |pq| pq.as_str()));
+                    respond_redirect(session, &ctx.cert_store, host_config, 301, location).await?;
+                    return Ok(true);
+                }
+                let Some(path) = normalize_request_path(uri.path()) else {
+                    info!("Rejected request path [{}] for [{}]", uri.path(), server_name);
+                    error_pages::respond(session, &ctx.cert_store, Some(host_config), 400, false).await?;
+                    return Ok(true);
+                };
+                ctx.location = host_config.find_location(&path).cloned();
+                if let Some((scope, rate_limit)) = host_config.rate_limit_for(ctx.location.as_ref()) {
+                    if let Some(retry_after) = rate_limit::check(&scope, rate_limit, session.req_header(), &ctx.client_ip) {
+                        let headers = [(http::header::RETRY_AFTER, retry_after.to_string())];
+                        respond_with_body(session, &ctx.cert_store, Some(host_config), 429, &headers, Bytes::new()).await?;
+                        return Ok(true);
+                    }
+                }
+                if let Some(auth) = host_config.basic_auth_for(ctx.location.as_ref()).filter(|auth| !auth.is_exempt(&path)) {
+                    let authorization = session.req_header().headers.get(http::header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+                    if !basic_auth::is_authorized(auth, authorization).await {
+                        let headers = [(http::header::WWW_AUTHENTICATE, format!("Basic realm=\"{}\", charset=\"UTF-8\"", auth.realm()))];
+                        respond_with_body(session, &ctx.cert_store, Some(host_config), 401, &headers, Bytes::new()).await?;
+                        return Ok(true);
+                    }
+                }
+                if let Some(static_files) = host_config.static_files_for(ctx.location.as_ref()) {
+                    static_files::serve(session, &ctx.cert_store, host_config, static_files).await?;
+                    return Ok(true);
+                }
+            }
             Ok(false)
         }
 
@@ -113,12 +440,21 @@ pub mod server {
 
             let host_name = SimpleHttpProxy::get_host(session);
             if host_name.is_none() {
-                error!("No host specified!");
-                let _ = session.respond_error(502).await;
+                // Answered with the error page by request_filter
                 return Ok(());
             }
 
             ctx.server_name = Some(host_name.unwrap().to_string());
+            ctx.request_id = Uuid::new_v4().to_string();
+            if grpc::is_grpc(session.req_header()) {
+                // Compression would break the length prefixed gRPC messages
+                if let Some(compression) = session.downstream_modules_ctx.get_mut::<ResponseCompression>() {
+                    compression.adjust_level(0);
+                }
+            }
+            if let Some(ip_str) = session.client_addr().and_then(|addr| addr.as_inet().map(|addr| addr.ip().to_string())) {
+                ctx.client_ip = ip_str;
+            }
 
             Ok(())
         }
@@ -129,26 +465,52 @@ pub mod server {
         {
             _upstream_request.insert_header("X-Forwarded-Proto", "https").expect("TODO: panic message");
             _upstream_request.insert_header("X-Forwarded-Scheme", "https").expect("TODO: panic message");
-            if let Some(ip_str) = _session.client_addr().and_then(|addr| addr.as_inet().map(|addr| addr.ip().to_string())) {
-                _ctx.client_ip = ip_str.clone();
-                _upstream_request.insert_header("X-Real-IP",ip_str).expect("Cannot add X-Real-IP");
+            if !_ctx.client_ip.is_empty() {
+                _upstream_request.insert_header("X-Real-IP", _ctx.client_ip.clone()).expect("Cannot add X-Real-IP");
             }
             // Replace Cookies with Compressed cookies
             let parsed_cookies: Vec<&str> = _upstream_request.as_ref().headers.get_all(http::header::COOKIE).iter().map(|x| { x.to_str().unwrap()}).collect();
             let compressed_cookies = parsed_cookies.join("; ");
             _upstream_request.insert_header("Cookie", compressed_cookies).expect("Failed replace/add Cookies");
+            // Only the upstream request is rewritten, the downstream request keeps the original URI for logging
+            if let Some(rewrite) = _ctx.location.as_ref().and_then(|l| l.rewrite.as_ref()) {
+                let uri = &_upstream_request.uri;
+                let Some(path) = rewrite.rewrite_path(uri.path()) else {
+                    return Err(upstream_error(400, "Invalid request path"));
+                };
+                let path_and_query = match uri.query() {
+                    Some(query) => format!("{}?{}", path, query),
+                    None => path,
+                };
+                match path_and_query.parse::<http::Uri>() {
+                    Ok(rewritten) => _upstream_request.set_uri(rewritten),
+                    Err(e) => {
+                        error!("Invalid rewritten uri [{}]: {}", path_and_query, e);
+                        return Err(upstream_error(500, "Invalid rewritten uri"));
+                    }
+                }
+            }
+            if let Some(host_config) = &_ctx.host_config {
+                for headers in host_config.headers_for(_ctx.location.as_ref()) {
+                    if let Some(rules) = &headers.request {
+                        apply_request_rules(_upstream_request, rules, &_ctx.header_vars());
+                    }
+                }
+            }
             Ok(())
         }
 
         async fn logging(&self, session: &mut Session, _e: Option<&Error>, _ctx: &mut Self::CTX) {
+            _ctx.release_upstream();
             let response_code = session
               .response_written()
               .map_or(0, |resp| resp.status.as_u16());
-            let log_msg = format!("[{}] [{}] [{}] - [{}{}]", _ctx.client_ip,
-                                  response_code.to_string(),
-                                  session.req_header().method.to_string(),
+            let log_msg = format!("[{}] [{}] [{}] - [{}{}] [{}]", _ctx.client_ip,
+                                  response_code,
+                                  session.req_header().method,
                                   _ctx.server_name.as_deref().unwrap_or(""),
-                                  session.req_header().uri.path_and_query().unwrap().to_string());
+                                  session.req_header().uri.path_and_query().unwrap(),
+                                  _ctx.location.as_ref().map_or("", |l| l.path.as_str()));
             // Log only global err`ors here
             if response_code > 307 {
                 error!("{}", log_msg);
@@ -182,17 +544,13 @@ pub mod server {
         type CTX = HttpCtx;
 
         fn new_ctx(&self) -> Self::CTX {
-            HttpCtx {
-                server_name: None,
-                cert_store: CertStore::new(),
-                client_ip: String::new(),
-            }
+            HttpCtx::new()
         }
 
         async fn upstream_peer(
             &self,
             _session: &mut Session,
-            ctx: &mut Self::CTX,
+            _ctx: &mut Self::CTX,
         ) -> Result<Box<HttpPeer>> {
             info!("PEER");
             todo!()
@@ -248,10 +606,10 @@ pub mod server {
               .response_written()
               .map_or(0, |resp| resp.status.as_u16());
             let log_msg = format!("[{}] [{}] [{}] - [{}{}]", _ctx.client_ip,
-                                  response_code.to_string(),
-                                  session.req_header().method.to_string(),
+                                  response_code,
+                                  session.req_header().method,
                                   _ctx.server_name.as_deref().unwrap_or(""),
-                                  session.req_header().uri.path_and_query().unwrap().to_string());
+                                  session.req_header().uri.path_and_query().unwrap());
             info!("{:?}", _e);
             // Log only global errors here
             if response_code > 307 {
@@ -325,6 +683,20 @@ pub mod server {
             info!("No or Invalid HTTPS Port Set - HTTPS Disabled!");
         }
 
+        let api_port = std::env::var("MPROXY_API_PORT").unwrap_or(String::from("0")).parse::<u16>().unwrap_or(0);
+        if api_port > 0 {
+            match std::env::var("MPROXY_API_TOKEN") {
+                Ok(token) if !token.is_empty() => {
+                    let api_address = std::env::var("MPROXY_API_ADDRESS").unwrap_or(String::from("127.0.0.1"));
+                    info!("Admin API Enabled - Address: [{}:{}]", api_address, api_port);
+                    let mut api = Service::new("Admin API".to_string(), HttpServer::new_app(AdminApi::new(token)));
+                    api.add_tcp(format!("{}:{}", api_address, api_port).as_str());
+                    pingora_server.add_service(api);
+                }
+                _ => warn!("MPROXY_API_TOKEN not set - Admin API disabled!"),
+            }
+        }
+
         pingora_server.run(RunArgs::default());
     }
 }
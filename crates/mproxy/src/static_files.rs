@@ -0,0 +1,353 @@
+use std::fs::Metadata;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::{error, info};
+use mproxy_common::host_config::{percent_decode, HostConfig, StaticFilesConfig};
+use crate::cert_store::CertStore;
+use crate::cors;
+use crate::error_pages;
+use crate::security_headers;
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Serves a request from the directory of a static files host or location
+pub async fn serve(session: &mut Session, cert_store: &CertStore, host_config: &HostConfig, config: &StaticFilesConfig) -> Result<()> {
+  let method = session.req_header().method.clone();
+  if method != http::Method::GET && method != http::Method::HEAD {
+    let headers = [(http::header::ALLOW, "GET, HEAD".to_string())];
+    return error_pages::respond_with_body(session, cert_store, Some(host_config), 405, &headers, Bytes::new()).await;
+  }
+
+  let Some(file_path) = resolve_file(config, session.req_header().uri.path()).await else {
+    return error_pages::respond(session, cert_store, Some(host_config), 404, false).await;
+  };
+  let metadata = match tokio::fs::metadata(&file_path).await {
+    Ok(metadata) => metadata,
+    Err(_) => return error_pages::respond(session, cert_store, Some(host_config), 404, false).await,
+  };
+
+  let etag = etag(&metadata);
+  let last_modified = modified(&metadata);
+  if is_not_modified(session.req_header(), &etag, last_modified) {
+    let mut response_header = ResponseHeader::build(304, Some(2))?;
+    response_header.insert_header(http::header::ETAG, etag.as_str())?;
+    if let Some(last_modified) = last_modified {
+      response_header.insert_header(http::header::LAST_MODIFIED, last_modified.format(HTTP_DATE_FORMAT).to_string())?;
+    }
+    security_headers::apply(&mut response_header, cert_store, Some(host_config))?;
+    cors::apply(session.req_header(), &mut response_header, Some(host_config))?;
+    session.write_response_header(Box::new(response_header), true).await?;
+    return Ok(());
+  }
+
+  let file_len = metadata.len();
+  let range = match requested_range(session.req_header(), &etag, file_len) {
+    Ok(range) => range,
+    Err(()) => {
+      let mut response_header = ResponseHeader::build(416, Some(2))?;
+      response_header.insert_header(http::header::CONTENT_RANGE, format!("bytes */{}", file_len))?;
+      response_header.insert_header(http::header::CONTENT_LENGTH, "0")?;
+      security_headers::apply(&mut response_header, cert_store, Some(host_config))?;
+      cors::apply(session.req_header(), &mut response_header, Some(host_config))?;
+      session.write_response_header(Box::new(response_header), true).await?;
+      return Ok(());
+    }
+  };
+  let (start, end) = range.unwrap_or((0, file_len.saturating_sub(1)));
+  let content_len = if file_len == 0 { 0 } else { end - start + 1 };
+
+  let mut response_header = ResponseHeader::build(if range.is_some() { 206 } else { 200 }, Some(6))?;
+  let content_type = mime_guess::from_path(&file_path).first_or_octet_stream();
+  response_header.insert_header(http::header::CONTENT_TYPE, content_type.essence_str())?;
+  response_header.insert_header(http::header::CONTENT_LENGTH, content_len.to_string())?;
+  response_header.insert_header(http::header::ACCEPT_RANGES, "bytes")?;
+  response_header.insert_header(http::header::ETAG, etag.as_str())?;
+  if let Some(last_modified) = last_modified {
+    response_header.insert_header(http::header::LAST_MODIFIED, last_modified.format(HTTP_DATE_FORMAT).to_string())?;
+  }
+  if range.is_some() {
+    response_header.insert_header(http::header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, file_len))?;
+  }
+  security_headers::apply(&mut response_header, cert_store, Some(host_config))?;
+  cors::apply(session.req_header(), &mut response_header, Some(host_config))?;
+
+  if method == http::Method::HEAD || content_len == 0 {
+    session.write_response_header(Box::new(response_header), true).await?;
+    return Ok(());
+  }
+
+  let mut file = match tokio::fs::File::open(&file_path).await {
+    Ok(file) => file,
+    Err(e) => {
+      error!("Cannot open static file [{}]: {}", file_path.display(), e);
+      return error_pages::respond(session, cert_store, Some(host_config), 500, false).await;
+    }
+  };
+  session.write_response_header(Box::new(response_header), false).await?;
+  if start > 0 {
+    file
+      .seek(SeekFrom::Start(start))
+      .await
+      .or_err(ReadError, "seeking static file")?;
+  }
+  let mut remaining = content_len;
+  let mut buf = vec![0u8; READ_CHUNK_SIZE];
+  while remaining > 0 {
+    let to_read = remaining.min(READ_CHUNK_SIZE as u64) as usize;
+    let read = file
+      .read(&mut buf[..to_read])
+      .await
+      .or_err(ReadError, "reading static file")?;
+    if read == 0 {
+      // The file got shorter while serving it
+      return Error::e_explain(ReadError, "static file truncated");
+    }
+    remaining -= read as u64;
+    session
+      .write_response_body(Some(Bytes::copy_from_slice(&buf[..read])), remaining == 0)
+      .await?;
+  }
+  Ok(())
+}
+
+/// Maps the request path to a file below the root, rejecting anything that would escape it
+async fn resolve_file(config: &StaticFilesConfig, request_path: &str) -> Option<PathBuf> {
+  let root = tokio::fs::canonicalize(&config.root).await.ok()?;
+  let decoded_path = percent_decode(request_path)?;
+  let mut path = root.clone();
+  for segment in decoded_path.split('/') {
+    match segment {
+      "" | "." => continue,
+      ".." => {
+        info!("Rejected path traversal: [{}]", request_path);
+        return None;
+      }
+      segment if segment.contains('\\') || segment.contains('\0') => return None,
+      segment => path.push(segment),
+    }
+  }
+
+  let mut candidates = Vec::new();
+  if tokio::fs::metadata(&path).await.is_ok_and(|metadata| metadata.is_dir()) {
+    candidates.extend(config.index_files().iter().map(|index| path.join(index)));
+  } else {
+    candidates.push(path);
+  }
+  if config.spa_fallback.unwrap_or(false) {
+    candidates.extend(config.index_files().first().map(|index| root.join(index)));
+  }
+  // Symlinks must not lead outside of the root either
+  for candidate in candidates {
+    let Ok(candidate) = tokio::fs::canonicalize(&candidate).await else {
+      continue;
+    };
+    if candidate.starts_with(&root) && tokio::fs::metadata(&candidate).await.is_ok_and(|metadata| metadata.is_file()) {
+      return Some(candidate);
+    }
+  }
+  None
+}
+
+fn modified(metadata: &Metadata) -> Option<DateTime<Utc>> {
+  let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+  DateTime::from_timestamp(modified.as_secs() as i64, 0)
+}
+
+fn etag(metadata: &Metadata) -> String {
+  let modified = modified(metadata).map_or(0, |modified| modified.timestamp());
+  format!("\"{:x}-{:x}\"", modified, metadata.len())
+}
+
+fn header_str(req: &RequestHeader, name: http::header::HeaderName) -> Option<&str> {
+  req.headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn is_not_modified(req: &RequestHeader, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+  // If-None-Match takes precedence over If-Modified-Since
+  if let Some(if_none_match) = header_str(req, http::header::IF_NONE_MATCH) {
+    return if_none_match
+      .split(',')
+      .map(|tag| tag.trim().trim_start_matches("W/"))
+      .any(|tag| tag == "*" || tag == etag);
+  }
+  match (header_str(req, http::header::IF_MODIFIED_SINCE), last_modified) {
+    (Some(if_modified_since), Some(last_modified)) => DateTime::parse_from_rfc2822(if_modified_since)
+      .is_ok_and(|since| last_modified <= since.with_timezone(&Utc)),
+    _ => false,
+  }
+}
+
+/// The single byte range of the request, `Ok(None)` serves the whole file and `Err` means unsatisfiable
+fn requested_range(req: &RequestHeader, etag: &str, file_len: u64) -> std::result::Result<Option<(u64, u64)>, ()> {
+  let Some(range) = header_str(req, http::header::RANGE) else {
+    return Ok(None);
+  };
+  // A stale If-Range validator means the client wants the whole (changed) file
+  if header_str(req, http::header::IF_RANGE).is_some_and(|if_range| if_range != etag) {
+    return Ok(None);
+  }
+  let Some(spec) = range.strip_prefix("bytes=") else {
+    return Ok(None);
+  };
+  // Multiple ranges are answered with the whole file
+  if spec.contains(',') {
+    return Ok(None);
+  }
+  let Some((start, end)) = spec.trim().split_once('-') else {
+    return Ok(None);
+  };
+  let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+    (Ok(start), Ok(end)) if start <= end => (start, end.min(file_len.saturating_sub(1))),
+    (Ok(start), Err(_)) if end.is_empty() => (start, file_len.saturating_sub(1)),
+    // Suffix range: the last n bytes
+    (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => (file_len.saturating_sub(suffix), file_len.saturating_sub(1)),
+    _ => return Ok(None),
+  };
+  if file_len == 0 || range.0 >= file_len {
+    return Err(());
+  }
+  Ok(Some(range))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::os::unix::fs::symlink;
+  use uuid::Uuid;
+
+  /// A static root with an `outside` directory next to it, removed on drop
+  struct Fixture {
+    dir: PathBuf,
+  }
+
+  impl Fixture {
+    fn new() -> Self {
+      let dir = std::env::temp_dir().join(format!("mproxy-static-{}", Uuid::new_v4()));
+      let root = dir.join("root");
+      std::fs::create_dir_all(root.join("sub")).unwrap();
+      std::fs::create_dir_all(dir.join("outside")).unwrap();
+      std::fs::write(root.join("index.html"), "index").unwrap();
+      std::fs::write(root.join("sub/index.html"), "sub index").unwrap();
+      std::fs::write(root.join("a.txt"), "a").unwrap();
+      std::fs::write(dir.join("outside/secret.txt"), "secret").unwrap();
+      symlink(dir.join("outside/secret.txt"), root.join("link")).unwrap();
+      symlink(dir.join("outside"), root.join("dirlink")).unwrap();
+      Self { dir }
+    }
+
+    fn config(&self, spa_fallback: bool) -> StaticFilesConfig {
+      StaticFilesConfig {
+        root: self.dir.join("root").to_string_lossy().to_string(),
+        index: None,
+        spa_fallback: Some(spa_fallback),
+      }
+    }
+
+    fn root_file(&self, path: &str) -> Option<PathBuf> {
+      Some(self.dir.join("root").join(path).canonicalize().unwrap())
+    }
+  }
+
+  impl Drop for Fixture {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.dir);
+    }
+  }
+
+  #[tokio::test]
+  async fn resolve_file_serves_files_and_index_files() {
+    let fixture = Fixture::new();
+    let config = fixture.config(false);
+    assert_eq!(resolve_file(&config, "/a.txt").await, fixture.root_file("a.txt"));
+    assert_eq!(resolve_file(&config, "/%61.txt").await, fixture.root_file("a.txt"));
+    assert_eq!(resolve_file(&config, "/").await, fixture.root_file("index.html"));
+    assert_eq!(resolve_file(&config, "/sub/").await, fixture.root_file("sub/index.html"));
+    assert_eq!(resolve_file(&config, "/sub").await, fixture.root_file("sub/index.html"));
+    assert_eq!(resolve_file(&config, "/missing").await, None);
+  }
+
+  #[tokio::test]
+  async fn resolve_file_rejects_path_traversal() {
+    let fixture = Fixture::new();
+    let config = fixture.config(true);
+    assert_eq!(resolve_file(&config, "/../outside/secret.txt").await, None);
+    assert_eq!(resolve_file(&config, "/sub/../../outside/secret.txt").await, None);
+    assert_eq!(resolve_file(&config, "/%2e%2e/outside/secret.txt").await, None);
+    assert_eq!(resolve_file(&config, "/..%2foutside%2fsecret.txt").await, None);
+    assert_eq!(resolve_file(&config, "/..\\outside\\secret.txt").await, None);
+    assert_eq!(resolve_file(&config, "/a%00.txt").await, None);
+    assert_eq!(resolve_file(&config, "/a%zz").await, None);
+  }
+
+  #[tokio::test]
+  async fn resolve_file_rejects_symlinks_leaving_the_root() {
+    let fixture = Fixture::new();
+    let config = fixture.config(false);
+    assert_eq!(resolve_file(&config, "/link").await, None);
+    assert_eq!(resolve_file(&config, "/dirlink/secret.txt").await, None);
+  }
+
+  #[tokio::test]
+  async fn resolve_file_falls_back_to_the_index_for_single_page_apps() {
+    let fixture = Fixture::new();
+    let config = fixture.config(true);
+    assert_eq!(resolve_file(&config, "/some/client/route").await, fixture.root_file("index.html"));
+    assert_eq!(resolve_file(&config, "/a.txt").await, fixture.root_file("a.txt"));
+  }
+
+  fn range(range: Option<&str>, if_range: Option<&str>, file_len: u64) -> std::result::Result<Option<(u64, u64)>, ()> {
+    let mut req = RequestHeader::build("GET", b"/file", None).unwrap();
+    if let Some(range) = range {
+      req.insert_header(http::header::RANGE, range).unwrap();
+    }
+    if let Some(if_range) = if_range {
+      req.insert_header(http::header::IF_RANGE, if_range).unwrap();
+    }
+    requested_range(&req, "\"etag\"", file_len)
+  }
+
+  #[test]
+  fn requested_range_parses_single_ranges() {
+    assert_eq!(range(None, None, 1000), Ok(None));
+    assert_eq!(range(Some("bytes=0-99"), None, 1000), Ok(Some((0, 99))));
+    assert_eq!(range(Some("bytes=900-"), None, 1000), Ok(Some((900, 999))));
+    assert_eq!(range(Some("bytes=-100"), None, 1000), Ok(Some((900, 999))));
+    assert_eq!(range(Some("bytes=999-999"), None, 1000), Ok(Some((999, 999))));
+  }
+
+  #[test]
+  fn requested_range_clamps_to_the_file() {
+    assert_eq!(range(Some("bytes=500-2000"), None, 1000), Ok(Some((500, 999))));
+    assert_eq!(range(Some("bytes=-2000"), None, 1000), Ok(Some((0, 999))));
+  }
+
+  #[test]
+  fn requested_range_rejects_unsatisfiable_ranges() {
+    assert_eq!(range(Some("bytes=1000-"), None, 1000), Err(()));
+    assert_eq!(range(Some("bytes=1000-1001"), None, 1000), Err(()));
+    assert_eq!(range(Some("bytes=0-0"), None, 0), Err(()));
+    assert_eq!(range(Some("bytes=-5"), None, 0), Err(()));
+  }
+
+  #[test]
+  fn requested_range_serves_the_whole_file_otherwise() {
+    assert_eq!(range(Some("bytes=5-4"), None, 1000), Ok(None));
+    assert_eq!(range(Some("bytes=0-1,5-6"), None, 1000), Ok(None));
+    assert_eq!(range(Some("items=0-1"), None, 1000), Ok(None));
+    assert_eq!(range(Some("bytes=-0"), None, 1000), Ok(None));
+    assert_eq!(range(Some("bytes=abc"), None, 1000), Ok(None));
+  }
+
+  #[test]
+  fn requested_range_honours_if_range() {
+    assert_eq!(range(Some("bytes=0-9"), Some("\"etag\""), 1000), Ok(Some((0, 9))));
+    assert_eq!(range(Some("bytes=0-9"), Some("\"changed\""), 1000), Ok(None));
+  }
+}
@@ -0,0 +1,230 @@
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use pingora::lb::Backend;
+use pingora::listeners::ALPN;
+use pingora::prelude::*;
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::protocols::TcpKeepalive;
+use pingora::tls::pkey::PKey;
+use pingora::tls::x509::X509;
+use pingora::upstreams::peer::PeerOptions;
+use pingora::utils::tls::CertKey;
+use tracing::{error, warn};
+use mproxy_common::host_config::{Upstream, UpstreamConnectionConfig, UpstreamProtocol, UpstreamTlsConfig};
+
+/// The host name of the configured upstream address a backend was resolved from
+#[derive(Clone, Debug)]
+pub struct UpstreamHostName(pub String);
+
+impl UpstreamHostName {
+  pub fn attach(backend: &mut Backend, target: &Upstream) {
+    if let Some(host_name) = target.host_name() {
+      backend.ext.insert(UpstreamHostName(host_name.to_string()));
+    }
+  }
+}
+
+/// TLS material of the upstream connections, loaded once per pool
+struct UpstreamTls {
+  sni: Option<String>,
+  verify: bool,
+  ca: Option<Arc<Box<[X509]>>>,
+  client_cert_key: Option<Arc<CertKey>>,
+}
+
+impl UpstreamTls {
+  fn load(pool_key: &str, config: &UpstreamTlsConfig) -> Self {
+    let ca = config.ca_file.as_ref().and_then(|ca_file| {
+      match fs::read(ca_file).map_err(|e| e.to_string()).and_then(|pem| X509::stack_from_pem(&pem).map_err(|e| e.to_string())) {
+        Ok(certs) => Some(Arc::new(certs.into_boxed_slice())),
+        Err(e) => {
+          error!("Cannot load upstream CA bundle [{}] for [{}]: {}", ca_file, pool_key, e);
+          None
+        }
+      }
+    });
+    let client_cert_key = match (&config.client_cert_file, &config.client_key_file) {
+      (Some(cert_file), Some(key_file)) => match Self::load_cert_key(cert_file, key_file) {
+        Ok(cert_key) => Some(Arc::new(cert_key)),
+        Err(e) => {
+          error!("Cannot load upstream client certificate [{}] for [{}]: {}", cert_file, pool_key, e);
+          None
+        }
+      },
+      (None, None) => None,
+      _ => {
+        error!("Upstream client certificate for [{}] needs both client_cert_file and client_key_file", pool_key);
+        None
+      }
+    };
+    Self {
+      sni: config.sni.clone(),
+      verify: config.verify(),
+      ca,
+      client_cert_key,
+    }
+  }
+
+  fn load_cert_key(cert_file: &str, key_file: &str) -> Result<CertKey, String> {
+    let certs = fs::read(cert_file)
+      .map_err(|e| e.to_string())
+      .and_then(|pem| X509::stack_from_pem(&pem).map_err(|e| e.to_string()))?;
+    let key = fs::read(key_file)
+      .map_err(|e| e.to_string())
+      .and_then(|pem| PKey::private_key_from_pem(&pem).map_err(|e| e.to_string()))?;
+    Ok(CertKey::new(certs, key))
+  }
+}
+
+/// Builds the peers used to connect to the backends of an upstream pool
+pub struct UpstreamPeerBuilder {
+  tls: Option<UpstreamTls>,
+  alpn: ALPN,
+  connection: UpstreamConnectionConfig,
+}
+
+impl Debug for UpstreamPeerBuilder {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "UpstreamPeerBuilder [tls: {}] [alpn: {:?}]", self.tls.is_some(), self.alpn)
+  }
+}
+
+impl UpstreamPeerBuilder {
+  pub fn new(
+    pool_key: &str,
+    upstream_tls: Option<&UpstreamTlsConfig>,
+    protocol: UpstreamProtocol,
+    connection: Option<&UpstreamConnectionConfig>,
+  ) -> Self {
+    let tls = upstream_tls
+      .filter(|config| config.is_enabled())
+      .map(|config| UpstreamTls::load(pool_key, config));
+    // Without TLS there is no ALPN, pingora speaks HTTP/1.1 unless HTTP/2 is required (h2c with prior knowledge)
+    match (protocol, tls.is_some()) {
+      (UpstreamProtocol::Http2, false) => warn!("Upstream [{}] uses http2 without upstream_tls - connecting with HTTP/1.1", pool_key),
+      (UpstreamProtocol::Http2Only, false) => warn!("Upstream [{}] uses http2_only without upstream_tls - connecting with h2c", pool_key),
+      (UpstreamProtocol::H2c, true) => warn!("Upstream [{}] uses h2c with upstream_tls - connecting with http2 over TLS", pool_key),
+      _ => {}
+    }
+    let alpn = match protocol {
+      UpstreamProtocol::Http1 => ALPN::H1,
+      // Lets upstreams without HTTP/2 support answer with HTTP/1.1
+      UpstreamProtocol::Http2 => ALPN::H2H1,
+      UpstreamProtocol::Http2Only | UpstreamProtocol::H2c => ALPN::H2,
+    };
+    Self {
+      tls,
+      alpn,
+      connection: connection.cloned().unwrap_or_default(),
+    }
+  }
+
+  pub fn new_peer(&self, backend: &Backend) -> Result<HttpPeer> {
+    let sni = match &self.tls {
+      Some(tls) => tls
+        .sni
+        .clone()
+        .or_else(|| backend.ext.get::<UpstreamHostName>().map(|host_name| host_name.0.clone()))
+        .unwrap_or_default(),
+      None => String::new(),
+    };
+    if self.tls.as_ref().is_some_and(|tls| tls.verify) && sni.is_empty() {
+      // pingora turns the certificate verification off without SNI
+      return Error::e_explain(InternalError, "upstream_tls needs an sni to verify an upstream without host name");
+    }
+    let mut peer = match &backend.addr {
+      SocketAddr::Inet(addr) => HttpPeer::new(*addr, self.tls.is_some(), sni),
+      SocketAddr::Unix(addr) => {
+        let path = addr
+          .as_pathname()
+          .and_then(|path| path.to_str())
+          .or_err(InternalError, "unix socket upstream without path")?;
+        HttpPeer::new_uds(path, self.tls.is_some(), sni)?
+      }
+    };
+    let mut peer_options = PeerOptions::new();
+    peer_options.connection_timeout = self.connection.connect_timeout();
+    peer_options.total_connection_timeout = self.connection.tls_connect_timeout();
+    peer_options.read_timeout = self.connection.read_timeout();
+    peer_options.write_timeout = self.connection.write_timeout();
+    peer_options.idle_timeout = Some(self.connection.idle_timeout());
+    peer_options.alpn = self.alpn.clone();
+    peer_options.max_h2_streams = self.connection.max_h2_streams();
+    // TCP socket options do not apply to unix domain sockets
+    if backend.addr.as_inet().is_some() {
+      peer_options.tcp_fast_open = self.connection.tcp_fast_open();
+      peer_options.tcp_keepalive = self.connection.tcp_keepalive().map(|keepalive| TcpKeepalive {
+        count: keepalive.count(),
+        idle: keepalive.idle(),
+        interval: keepalive.interval(),
+        #[cfg(target_os = "linux")]
+        user_timeout: Duration::from_secs(0),
+      });
+    }
+    peer_options.extra_proxy_headers.insert("X-Forwarded-Proto".to_string(), "https".as_bytes().to_vec());
+    peer_options.extra_proxy_headers.insert("X-Forwarded-For".to_string(), peer._address.to_string().as_bytes().to_vec());
+    if let Some(tls) = &self.tls {
+      peer_options.verify_cert = tls.verify;
+      peer_options.verify_hostname = tls.verify;
+      peer_options.ca = tls.ca.clone();
+      peer.client_cert_key = tls.client_cert_key.clone();
+    }
+    peer.options = peer_options;
+    Ok(peer)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tls_builder(config: UpstreamTlsConfig) -> UpstreamPeerBuilder {
+    UpstreamPeerBuilder::new("test", Some(&config), UpstreamProtocol::Http1, None)
+  }
+
+  fn backend(address: &str, host_name: Option<&str>) -> Backend {
+    let mut backend = Backend::new(address).unwrap();
+    if let Some(host_name) = host_name {
+      backend.ext.insert(UpstreamHostName(host_name.to_string()));
+    }
+    backend
+  }
+
+  #[test]
+  fn verified_tls_to_an_ip_address_needs_sni() {
+    let builder = tls_builder(UpstreamTlsConfig {
+      ca_file: Some("/nonexistent/ca.pem".to_string()),
+      ..Default::default()
+    });
+    assert!(builder.new_peer(&backend("10.0.0.1:443", None)).is_err());
+
+    let peer = builder.new_peer(&backend("10.0.0.1:443", Some("backend.internal"))).unwrap();
+    assert_eq!(peer.sni, "backend.internal");
+    assert!(peer.options.verify_cert);
+  }
+
+  #[test]
+  fn explicit_sni_or_skipped_verification_connects_to_an_ip_address() {
+    let peer = tls_builder(UpstreamTlsConfig {
+      sni: Some("backend.internal".to_string()),
+      ..Default::default()
+    }).new_peer(&backend("10.0.0.1:443", None)).unwrap();
+    assert_eq!(peer.sni, "backend.internal");
+    assert!(peer.options.verify_cert);
+
+    let peer = tls_builder(UpstreamTlsConfig {
+      insecure_skip_verify: Some(true),
+      ..Default::default()
+    }).new_peer(&backend("10.0.0.1:443", None)).unwrap();
+    assert!(!peer.options.verify_cert);
+  }
+
+  #[test]
+  fn plain_upstreams_need_no_sni() {
+    let builder = UpstreamPeerBuilder::new("test", None, UpstreamProtocol::Http1, None);
+    let peer = builder.new_peer(&backend("10.0.0.1:80", None)).unwrap();
+    assert!(peer.sni.is_empty());
+  }
+}
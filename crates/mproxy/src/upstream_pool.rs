@@ -0,0 +1,392 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Debug, Formatter};
+use std::os::unix::net::SocketAddr as UnixSocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Instant;
+use pingora::lb::discovery::Static;
+use pingora::lb::selection::{Consistent, Random, RoundRobin};
+use pingora::lb::{Backend, Backends, LoadBalancer};
+use pingora::prelude::HttpPeer;
+use pingora::protocols::l4::socket::SocketAddr;
+use http::Extensions;
+use tracing::{error, info};
+use mproxy_common::host_config::{HealthCheckConfig, HostConfig, HostConfigList, LoadBalancing, LoadBalancingStrategy, Location, OutlierDetectionConfig, StickyConfig, Upstream, UpstreamConnectionConfig, UpstreamProtocol, UpstreamTlsConfig};
+use crate::health_check::UpstreamHealthCheck;
+use crate::outlier::OutlierDetector;
+use crate::upstream_peer::{UpstreamHostName, UpstreamPeerBuilder};
+
+// How many backends the selection algorithms may try before giving up
+const MAX_SELECT_ITERATIONS: usize = 256;
+
+// Global map of the upstream pools, keyed by HostConfig::pool_key
+static UPSTREAM_POOLS: LazyLock<Mutex<HashMap<String, Arc<UpstreamPool>>>> = LazyLock::new(|| {
+  info!("UPSTREAM_POOLS Init");
+  Mutex::new(HashMap::new())
+});
+
+/// Identifies a backend by address and weight. The key ends up in affinity cookies, so it is
+/// an FNV-1a hash that stays the same across restarts and Rust releases, unlike `DefaultHasher`
+pub fn backend_key(backend: &Backend) -> u64 {
+  let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+  for byte in format!("{}|{}", backend.addr, backend.weight).bytes() {
+    hash ^= u64::from(byte);
+    hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+  }
+  hash
+}
+
+enum Balancer {
+  RoundRobin(LoadBalancer<RoundRobin>),
+  WeightedRandom(LoadBalancer<Random>),
+  ConsistentHash(LoadBalancer<Consistent>),
+  // Least connections is not part of pingora, the RoundRobin balancer only keeps the backend list
+  LeastConnections(LoadBalancer<RoundRobin>),
+}
+
+/// The part of a HostConfig an upstream pool is built from
+#[derive(Debug, Clone, PartialEq)]
+struct PoolConfig {
+  targets: Vec<Upstream>,
+  load_balancing: LoadBalancing,
+  health_check: Option<HealthCheckConfig>,
+  outlier_detection: Option<OutlierDetectionConfig>,
+  upstream_tls: Option<UpstreamTlsConfig>,
+  upstream_protocol: UpstreamProtocol,
+  upstream_connection: Option<UpstreamConnectionConfig>,
+}
+
+impl PoolConfig {
+  fn new(host_config: &HostConfig, location: Option<&Location>) -> Self {
+    Self {
+      targets: host_config.upstreams_for(location),
+      load_balancing: host_config.load_balancing.clone().unwrap_or_default(),
+      health_check: host_config.health_check.clone(),
+      outlier_detection: host_config.outlier_detection.clone(),
+      upstream_tls: host_config.upstream_tls.clone(),
+      upstream_protocol: host_config.upstream_protocol,
+      upstream_connection: host_config.upstream_connection.clone(),
+    }
+  }
+}
+
+/// The balanced upstream targets of a host or location
+pub struct UpstreamPool {
+  key: String,
+  config: PoolConfig,
+  balancer: Balancer,
+  active_connections: HashMap<u64, AtomicUsize>,
+  last_health_check: Mutex<Option<Instant>>,
+  outlier: Option<OutlierDetector>,
+  peer_builder: Arc<UpstreamPeerBuilder>,
+}
+
+impl Debug for UpstreamPool {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "UpstreamPool [{}]", self.key)
+  }
+}
+
+/// The backend picked for a request, returned to the pool once the request is done
+#[derive(Debug)]
+pub struct SelectedUpstream {
+  pub pool: Arc<UpstreamPool>,
+  pub backend: Backend,
+  // Whether the outcome of the request was already reported to the outlier detection
+  reported: bool,
+}
+
+impl UpstreamPool {
+  async fn new(key: String, host_name: &str, config: PoolConfig) -> Self {
+    let backend_set = Self::resolve_backends(&key, &config.targets).await;
+    let active_connections = backend_set
+      .iter()
+      .map(|backend| (backend_key(backend), AtomicUsize::new(0)))
+      .collect();
+    let peer_builder = Arc::new(UpstreamPeerBuilder::new(
+      &key,
+      config.upstream_tls.as_ref(),
+      config.upstream_protocol,
+      config.upstream_connection.as_ref(),
+    ));
+    let mut backends = Backends::new(Static::new(backend_set));
+    if let Some(health_check) = &config.health_check {
+      backends.set_health_check(UpstreamHealthCheck::new(&key, host_name, health_check.clone(), peer_builder.clone()));
+    }
+    let balancer = match config.load_balancing.strategy {
+      LoadBalancingStrategy::RoundRobin => Balancer::RoundRobin(LoadBalancer::from_backends(backends)),
+      LoadBalancingStrategy::WeightedRandom => Balancer::WeightedRandom(LoadBalancer::from_backends(backends)),
+      LoadBalancingStrategy::ConsistentHash => Balancer::ConsistentHash(LoadBalancer::from_backends(backends)),
+      LoadBalancingStrategy::LeastConnections => Balancer::LeastConnections(LoadBalancer::from_backends(backends)),
+    };
+    let outlier = config
+      .outlier_detection
+      .clone()
+      .map(|outlier_detection| OutlierDetector::new(&key, outlier_detection));
+    let pool = Self {
+      key,
+      config,
+      balancer,
+      active_connections,
+      last_health_check: Mutex::new(None),
+      outlier,
+      peer_builder,
+    };
+    if let Err(e) = pool.update().await {
+      error!("Failed to initialize upstream pool [{}]: {}", pool.key, e);
+    }
+    info!("Upstream pool [{}] - {:?} {:?}", pool.key, pool.config.load_balancing.strategy, pool.config.targets);
+    pool
+  }
+
+  /// Resolves the targets without blocking, pools are rebuilt on config reloads while serving
+  async fn resolve_backends(key: &str, targets: &[Upstream]) -> BTreeSet<Backend> {
+    let mut backend_set = BTreeSet::new();
+    for target in targets {
+      let weight = target.weight.unwrap_or(1);
+      if let Some(socket_path) = target.unix_socket_path() {
+        match UnixSocketAddr::from_pathname(socket_path) {
+          Ok(addr) => {
+            backend_set.insert(Backend {
+              addr: SocketAddr::Unix(addr),
+              weight,
+              ext: Extensions::new(),
+            });
+          }
+          Err(e) => error!("Invalid unix socket upstream [{}] for [{}]: {}", target.address, key, e),
+        }
+        continue;
+      }
+      match tokio::net::lookup_host(target.address.as_str()).await {
+        Ok(addrs) => {
+          for addr in addrs {
+            match Backend::new_with_weight(&addr.to_string(), weight) {
+              Ok(mut backend) => {
+                UpstreamHostName::attach(&mut backend, target);
+                backend_set.insert(backend);
+              }
+              Err(e) => error!("Invalid upstream [{}] for [{}]: {}", target.address, key, e),
+            }
+          }
+        }
+        Err(e) => error!("Cannot resolve upstream [{}] for [{}]: {}", target.address, key, e),
+      }
+    }
+    backend_set
+  }
+
+  async fn update(&self) -> pingora::Result<()> {
+    match &self.balancer {
+      Balancer::RoundRobin(lb) => lb.update().await,
+      Balancer::WeightedRandom(lb) => lb.update().await,
+      Balancer::ConsistentHash(lb) => lb.update().await,
+      Balancer::LeastConnections(lb) => lb.update().await,
+    }
+  }
+
+  pub fn key(&self) -> &str {
+    &self.key
+  }
+
+  /// The request header used as consistent hashing key, `None` means the client IP.
+  /// Requests without the header are hashed by client IP as well
+  pub fn hash_header(&self) -> Option<&str> {
+    self.config.load_balancing.hash_header.as_deref()
+  }
+
+  /// The affinity cookie settings when sticky sessions are enabled
+  pub fn sticky(&self) -> Option<&StickyConfig> {
+    self.config.load_balancing.sticky.as_ref()
+  }
+
+  /// How often a failed request may be retried, `None` keeps the pingora defaults
+  pub fn retries(&self) -> Option<usize> {
+    self.config.upstream_connection.as_ref().and_then(|connection| connection.retries)
+  }
+
+  /// Builds the peer to connect to a selected backend
+  pub fn new_peer(&self, backend: &Backend) -> pingora::Result<HttpPeer> {
+    self.peer_builder.new_peer(backend)
+  }
+
+  fn backends(&self) -> &Backends {
+    match &self.balancer {
+      Balancer::RoundRobin(lb) => lb.backends(),
+      Balancer::WeightedRandom(lb) => lb.backends(),
+      Balancer::ConsistentHash(lb) => lb.backends(),
+      Balancer::LeastConnections(lb) => lb.backends(),
+    }
+  }
+
+  fn active(&self, backend: &Backend) -> usize {
+    self
+      .active_connections
+      .get(&backend_key(backend))
+      .map_or(0, |count| count.load(Ordering::Relaxed))
+  }
+
+  fn admit(&self, backend: &Backend) -> bool {
+    self.outlier.as_ref().is_none_or(|outlier| outlier.admit(backend))
+  }
+
+  /// Picks a healthy, not ejected backend for a request, `hash_key` is only used by consistent hashing
+  pub fn select(self: &Arc<Self>, hash_key: &[u8]) -> Option<SelectedUpstream> {
+    let accept = |backend: &Backend, healthy: bool| healthy && self.admit(backend);
+    let backend = match &self.balancer {
+      Balancer::RoundRobin(lb) => lb.select_with(hash_key, MAX_SELECT_ITERATIONS, accept),
+      Balancer::WeightedRandom(lb) => lb.select_with(hash_key, MAX_SELECT_ITERATIONS, accept),
+      Balancer::ConsistentHash(lb) => lb.select_with(hash_key, MAX_SELECT_ITERATIONS, accept),
+      Balancer::LeastConnections(_) => {
+        let backends = self.backends();
+        let backend_set = backends.get_backend();
+        let mut candidates: Vec<&Backend> = backend_set
+          .iter()
+          .filter(|backend| backends.ready(backend))
+          .filter(|backend| self.outlier.as_ref().is_none_or(|outlier| outlier.is_available(backend)))
+          .collect();
+        // A backend on probation may have no probe slot left, the next least loaded one takes over
+        candidates.sort_by_key(|backend| self.active(backend));
+        candidates.into_iter().find(|backend| self.admit(backend)).cloned()
+      }
+    }?;
+    Some(self.selected(backend))
+  }
+
+  /// Picks the backend of an affinity cookie if it is still part of the pool, healthy and not ejected
+  pub fn select_sticky(self: &Arc<Self>, affinity: &str) -> Option<SelectedUpstream> {
+    let backends = self.backends();
+    let backend = backends
+      .get_backend()
+      .iter()
+      .find(|backend| affinity_of(backend) == affinity)
+      .filter(|backend| backends.ready(backend) && self.admit(backend))
+      .cloned()?;
+    Some(self.selected(backend))
+  }
+
+  fn selected(self: &Arc<Self>, backend: Backend) -> SelectedUpstream {
+    if let Some(count) = self.active_connections.get(&backend_key(&backend)) {
+      count.fetch_add(1, Ordering::Relaxed);
+    }
+    SelectedUpstream {
+      pool: self.clone(),
+      backend,
+      reported: false,
+    }
+  }
+
+  fn release(&self, backend: &Backend) {
+    if let Some(count) = self.active_connections.get(&backend_key(backend)) {
+      let _ = count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| c.checked_sub(1));
+    }
+  }
+
+  /// Returns true (and starts a new interval) when the health check of the pool should run
+  fn health_check_due(&self) -> bool {
+    let Some(health_check) = &self.config.health_check else {
+      return false;
+    };
+    let mut last_health_check = self.last_health_check.lock().unwrap();
+    if last_health_check.is_some_and(|last| last.elapsed() < health_check.interval()) {
+      return false;
+    }
+    *last_health_check = Some(Instant::now());
+    true
+  }
+
+  async fn run_health_check(&self) {
+    self.backends().run_health_check(true).await;
+  }
+}
+
+// The affinity cookie value of a backend, its address is not exposed to clients
+fn affinity_of(backend: &Backend) -> String {
+  format!("{:016x}", backend_key(backend))
+}
+
+impl SelectedUpstream {
+  /// The affinity cookie value of the backend
+  pub fn affinity(&self) -> String {
+    affinity_of(&self.backend)
+  }
+
+  /// Records a successful response of the backend
+  pub fn report_success(&mut self) {
+    if let Some(outlier) = &self.pool.outlier {
+      outlier.report_success(&self.backend);
+    }
+    self.reported = true;
+  }
+
+  /// Records a connect error or 5xx response of the backend
+  pub fn report_failure(&mut self, reason: &str) {
+    if let Some(outlier) = &self.pool.outlier {
+      outlier.report_failure(&self.backend, reason);
+    }
+    self.reported = true;
+  }
+
+  /// Marks the request on the backend as finished
+  pub fn release(&self) {
+    self.pool.release(&self.backend);
+    if !self.reported {
+      if let Some(outlier) = &self.pool.outlier {
+        outlier.release_probe(&self.backend);
+      }
+    }
+  }
+}
+
+/// Returns the pool of a host/location, pools are only built by `sync_pools`
+pub fn pool_for(host_config: &HostConfig, location: Option<&Location>) -> Option<Arc<UpstreamPool>> {
+  UPSTREAM_POOLS.lock().unwrap().get(&host_config.pool_key(location)).cloned()
+}
+
+/// Keeps the pool of a host/location, or (re)builds it when its config is new or changed
+async fn sync_pool(host_config: &HostConfig, location: Option<&Location>) -> String {
+  let key = host_config.pool_key(location);
+  let config = PoolConfig::new(host_config, location);
+  if UPSTREAM_POOLS.lock().unwrap().get(&key).is_some_and(|pool| pool.config == config) {
+    return key;
+  }
+  let pool = Arc::new(UpstreamPool::new(key.clone(), &host_config.host_name, config).await);
+  UPSTREAM_POOLS.lock().unwrap().insert(key.clone(), pool);
+  key
+}
+
+/// Creates the pools of all hosts and locations up front and drops the ones no longer configured
+pub async fn sync_pools(host_config_list: &HostConfigList) {
+  let mut keys = Vec::new();
+  for host_config in &host_config_list.host_configs {
+    keys.push(sync_pool(host_config, None).await);
+    if let Some(locations) = &host_config.locations {
+      for location in locations.iter().filter(|l| l.has_upstreams()) {
+        keys.push(sync_pool(host_config, Some(location)).await);
+      }
+    }
+  }
+  UPSTREAM_POOLS.lock().unwrap().retain(|key, _| keys.contains(key));
+}
+
+/// Runs the health checks of all pools whose check interval has elapsed
+pub async fn run_health_checks() {
+  let pools: Vec<Arc<UpstreamPool>> = UPSTREAM_POOLS.lock().unwrap().values().cloned().collect();
+  for pool in pools.into_iter().filter(|pool| pool.health_check_due()) {
+    tokio::spawn(async move {
+      pool.run_health_check().await;
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn backend_key_is_stable() {
+    let backend = Backend::new_with_weight("127.0.0.1:8080", 1).unwrap();
+    assert_eq!(backend_key(&backend), 0x9da5_dfa9_d803_3813);
+    let weighted = Backend::new_with_weight("127.0.0.1:8080", 2).unwrap();
+    assert_ne!(backend_key(&backend), backend_key(&weighted));
+  }
+}
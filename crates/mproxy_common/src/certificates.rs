@@ -67,8 +67,8 @@ impl Certificate {
   }
 
   pub fn parse_inter_cert(&mut self){
-    if self.full_chain.is_some() {
-      if let Some(inter_cert_str) = Certificate::extract_inter_cert_str(self.full_chain.as_ref().unwrap()) {
+    if let Some(full_chain) = self.full_chain.as_ref() {
+      if let Some(inter_cert_str) = Certificate::extract_inter_cert_str(full_chain) {
         self.parsed_inter_cert = RefCell::new(Some(inter_cert_str.as_bytes().to_vec()));
       }
     }
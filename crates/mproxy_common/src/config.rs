@@ -20,6 +20,12 @@ impl Config {
   }
 }
 
+impl Default for Config {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 impl Dump for Config {
   fn dump(&self) {
     info!("Dump config");
@@ -1,24 +1,1148 @@
-use crate::data_path;
+use crate::{data_path, maintenance_path};
+use ipnet::IpNet;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::env;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct HostConfig {
+    /// Exact name, single label wildcard (`*.example.com`) or regex pattern prefixed with `~`
     pub host_name: String,
     pub aliases: Option<Vec<String>>,
-    pub upstream_address: String,
+    /// Directory name of the certificate in the cert path, defaults to the host name
+    pub certificate: Option<String>,
+    pub upstream_address: Option<String>,
+    /// Additional upstream targets, balanced together with `upstream_address`
+    pub upstreams: Option<Vec<Upstream>>,
+    pub load_balancing: Option<LoadBalancing>,
+    pub health_check: Option<HealthCheckConfig>,
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    pub upstream_tls: Option<UpstreamTlsConfig>,
+    /// HTTP version spoken to the upstream targets
+    #[serde(default)]
+    pub upstream_protocol: UpstreamProtocol,
+    /// Timeouts, keepalive and retries of the connections to the upstream targets
+    pub upstream_connection: Option<UpstreamConnectionConfig>,
+    /// Turns the host into a redirect-only host without upstreams
+    pub redirect: Option<RedirectConfig>,
+    /// Redirects requests for an alias to the (canonical) host name instead of proxying them
+    pub redirect_aliases: Option<bool>,
+    /// Serves files from a directory instead of proxying
+    pub static_files: Option<StaticFilesConfig>,
+    pub headers: Option<HeadersConfig>,
+    pub basic_auth: Option<BasicAuthConfig>,
+    /// Ordered allow/deny rules for the client address, the first matching rule decides
+    pub access: Option<Vec<AccessRule>>,
+    #[serde(skip)]
+    pub access_networks: Vec<(bool, Vec<IpNet>)>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub cache: Option<CacheConfig>,
+    pub maintenance: Option<MaintenanceConfig>,
+    /// Pages of the error responses generated by mproxy, before the global ones
+    pub error_pages: Option<ErrorPagesConfig>,
+    pub cors: Option<CorsConfig>,
+    /// Overrides single settings of the global security headers
+    pub security_headers: Option<SecurityHeadersConfig>,
+    pub locations: Option<Vec<Location>>,
+}
+
+/// A single upstream target of a host or location
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Upstream {
+    pub address: String,
+    pub weight: Option<usize>,
+}
+
+impl Upstream {
+    pub fn new(address: String) -> Self {
+        Upstream { address, weight: None }
+    }
+
+    /// The socket path of a `unix:/path/to/socket` address
+    pub fn unix_socket_path(&self) -> Option<&str> {
+        self.address.strip_prefix("unix:")
+    }
+
+    /// The host part of the address if it is a name rather than an IP address
+    pub fn host_name(&self) -> Option<&str> {
+        if self.unix_socket_path().is_some() {
+            return None;
+        }
+        let host = match self.address.rsplit_once(':') {
+            Some((host, _)) => host,
+            None => self.address.as_str(),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.parse::<std::net::IpAddr>().is_ok() {
+            None
+        } else {
+            Some(host)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancingStrategy {
+    #[default]
+    RoundRobin,
+    WeightedRandom,
+    LeastConnections,
+    ConsistentHash,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub struct LoadBalancing {
+    #[serde(default)]
+    pub strategy: LoadBalancingStrategy,
+    /// Request header used as key by `consistent_hash`, the client IP is used when not set or missing
+    pub hash_header: Option<String>,
+    /// Keeps a client on the same upstream with an affinity cookie
+    pub sticky: Option<StickyConfig>,
+}
+
+/// Affinity cookie of sticky sessions, the cookie identifies the upstream by a hash
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct StickyConfig {
+    pub cookie_name: Option<String>,
+    /// A session cookie is used when not set
+    pub ttl_secs: Option<u64>,
+}
+
+impl StickyConfig {
+    pub fn cookie_name(&self) -> &str {
+        self.cookie_name.as_deref().unwrap_or("mproxy_sticky")
+    }
+
+    /// The Set-Cookie value pinning the client to an upstream
+    pub fn set_cookie(&self, affinity: &str) -> String {
+        let mut cookie = format!("{}={}; Path=/; Secure; HttpOnly; SameSite=Lax", self.cookie_name(), affinity);
+        if let Some(ttl) = self.ttl_secs {
+            cookie.push_str(&format!("; Max-Age={}", ttl));
+        }
+        cookie
+    }
+}
+
+/// How a `Location` path is compared against the request path
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LocationMatch {
+    #[default]
+    Prefix,
+    Exact,
+    Regex,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckType {
+    #[default]
+    Tcp,
+    Http,
+}
+
+/// Active health check of the upstream targets of a host
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct HealthCheckConfig {
+    #[serde(default, rename = "type")]
+    pub check_type: HealthCheckType,
+    pub interval_secs: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    /// Consecutive successful checks before a target is marked healthy again
+    pub rise: Option<usize>,
+    /// Consecutive failed checks before a target is marked unhealthy
+    pub fall: Option<usize>,
+    /// Request path of the http check
+    pub path: Option<String>,
+    /// Host header of the http check, defaults to the host name
+    pub host: Option<String>,
+    pub expected_status: Option<u16>,
+    /// The http check fails when the response body does not contain this text
+    pub expected_body: Option<String>,
+}
+
+impl HealthCheckConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.unwrap_or(10))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms.unwrap_or(2000))
+    }
+
+    pub fn rise(&self) -> usize {
+        self.rise.unwrap_or(2)
+    }
+
+    pub fn fall(&self) -> usize {
+        self.fall.unwrap_or(3)
+    }
+}
+
+/// Passive failure tracking of the upstream targets of a host
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct OutlierDetectionConfig {
+    /// Consecutive connect errors or 5xx responses before a target is ejected
+    pub consecutive_failures: Option<usize>,
+    /// Ejection time of the first ejection, doubled for every further ejection in a row
+    pub ejection_secs: Option<u64>,
+    pub max_ejection_secs: Option<u64>,
+    /// Concurrent trial requests sent to a target once its ejection time is over
+    pub probe_requests: Option<usize>,
+}
+
+impl OutlierDetectionConfig {
+    pub fn consecutive_failures(&self) -> usize {
+        self.consecutive_failures.unwrap_or(5).max(1)
+    }
+
+    pub fn ejection_time(&self, ejections: u32) -> Duration {
+        let base = self.ejection_secs.unwrap_or(30);
+        let max = self.max_ejection_secs.unwrap_or(300).max(base);
+        Duration::from_secs(base.saturating_mul(1u64 << ejections.min(16)).min(max))
+    }
+
+    pub fn probe_requests(&self) -> usize {
+        self.probe_requests.unwrap_or(1).max(1)
+    }
+}
+
+/// HTTP version of the upstream connections
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    /// HTTP/2 negotiated via ALPN with a fallback to HTTP/1.1, needs `upstream_tls`
+    Http2,
+    /// HTTP/2 via ALPN without a fallback, for backends that only work with HTTP/2 (gRPC)
+    Http2Only,
+    /// HTTP/2 over cleartext with prior knowledge (gRPC without TLS)
+    H2c,
+}
+
+/// TCP keepalive probes of the upstream connections
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub struct TcpKeepaliveConfig {
+    /// Defaults to true when the section is present
+    pub enabled: Option<bool>,
+    pub idle_secs: Option<u64>,
+    pub interval_secs: Option<u64>,
+    pub count: Option<usize>,
+}
+
+impl TcpKeepaliveConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    pub fn idle(&self) -> Duration {
+        Duration::from_secs(self.idle_secs.unwrap_or(60))
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.unwrap_or(30))
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.unwrap_or(32)
+    }
+}
+
+/// Timeouts, keepalive and retry policy of the connections to the upstream targets of a host
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub struct UpstreamConnectionConfig {
+    /// TCP connect
+    pub connect_timeout_ms: Option<u64>,
+    /// TCP connect plus TLS handshake, not a timeout of the whole request
+    pub tls_connect_timeout_ms: Option<u64>,
+    /// Each read from the upstream
+    pub read_timeout_ms: Option<u64>,
+    /// Each write to the upstream
+    pub write_timeout_ms: Option<u64>,
+    /// How long an unused connection is kept for reuse, defaults to 120 seconds
+    pub idle_timeout_secs: Option<u64>,
+    pub tcp_fast_open: Option<bool>,
+    pub tcp_keepalive: Option<TcpKeepaliveConfig>,
+    /// Retries after connection failures and errors of idempotent requests, `None` keeps the pingora defaults
+    pub retries: Option<usize>,
+    /// Concurrent requests on one HTTP/2 connection before another connection is opened, defaults to 16
+    pub max_h2_streams: Option<usize>,
+}
+
+impl UpstreamConnectionConfig {
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn tls_connect_timeout(&self) -> Option<Duration> {
+        self.tls_connect_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs.unwrap_or(120))
+    }
+
+    pub fn max_h2_streams(&self) -> usize {
+        self.max_h2_streams.unwrap_or(16).max(1)
+    }
+
+    pub fn tcp_fast_open(&self) -> bool {
+        self.tcp_fast_open.unwrap_or(true)
+    }
+
+    /// The TCP keepalive settings, enabled with the defaults unless configured otherwise
+    pub fn tcp_keepalive(&self) -> Option<TcpKeepaliveConfig> {
+        let keepalive = self.tcp_keepalive.clone().unwrap_or_default();
+        keepalive.is_enabled().then_some(keepalive)
+    }
+}
+
+/// TLS settings of the connections to the upstream targets of a host
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub struct UpstreamTlsConfig {
+    /// Defaults to true when the section is present
+    pub enabled: Option<bool>,
+    /// SNI sent to the upstream, defaults to the host name of the upstream address
+    pub sni: Option<String>,
+    /// PEM bundle used instead of the system CAs to verify the upstream certificate
+    pub ca_file: Option<String>,
+    /// Disables certificate and host name verification, only meant for lab setups
+    pub insecure_skip_verify: Option<bool>,
+    /// PEM client certificate (chain) and key for mutual TLS
+    pub client_cert_file: Option<String>,
+    pub client_key_file: Option<String>,
+}
+
+impl UpstreamTlsConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    pub fn verify(&self) -> bool {
+        !self.insecure_skip_verify.unwrap_or(false)
+    }
+
+    /// Whether a target can only be verified with an explicit `sni`, pingora skips
+    /// the certificate verification when there is no SNI to send
+    pub fn needs_sni(&self, target: &Upstream) -> bool {
+        self.is_enabled() && self.verify() && self.sni.is_none() && target.host_name().is_none()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RedirectConfig {
+    /// Target URL, e.g. `https://example.com`
+    pub target: String,
+    /// One of 301, 302, 307 or 308, defaults to 301
+    pub status: Option<u16>,
+    /// Appends the request path to the target, defaults to true
+    pub preserve_path: Option<bool>,
+    /// Appends the query string of the request, defaults to true
+    pub preserve_query: Option<bool>,
+}
+
+impl RedirectConfig {
+    pub fn status(&self) -> u16 {
+        match self.status {
+            Some(status @ (301 | 302 | 307 | 308)) => status,
+            Some(status) => {
+                error!("Invalid redirect status [{}] for [{}] - using 301", status, self.target);
+                301
+            }
+            None => 301,
+        }
+    }
+
+    /// The `Location` of the redirect for a request
+    pub fn location(&self, path: &str, query: Option<&str>) -> String {
+        let mut location = self.target.clone();
+        if self.preserve_path.unwrap_or(true) {
+            location = format!("{}{}", location.trim_end_matches('/'), path);
+        }
+        if let Some(query) = query.filter(|_| self.preserve_query.unwrap_or(true)) {
+            location = format!("{}?{}", location, query);
+        }
+        location
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StaticFilesConfig {
+    /// Directory the request path is resolved against (like the nginx `root` directive)
+    pub root: String,
+    /// Files tried for directory requests, defaults to `index.html`
+    pub index: Option<Vec<String>>,
+    /// Serves the first index file of the root for paths that do not exist (single page apps)
+    pub spa_fallback: Option<bool>,
+}
+
+impl StaticFilesConfig {
+    pub fn index_files(&self) -> Vec<String> {
+        self.index.clone().unwrap_or_else(|| vec!["index.html".to_string()])
+    }
+}
+
+/// Header changes, applied in the order remove, set, append. Values may contain the variables
+/// `$client_ip`, `$host`, `$scheme` and `$request_id`
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct HeaderRules {
+    pub set: Option<BTreeMap<String, String>>,
+    pub append: Option<BTreeMap<String, String>>,
+    pub remove: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct HeadersConfig {
+    /// Headers of the request sent upstream
+    pub request: Option<HeaderRules>,
+    /// Headers of the response sent downstream
+    pub response: Option<HeaderRules>,
+}
+
+/// An access rule with a single address, a CIDR range or `all`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessRule {
+    Allow(String),
+    Deny(String),
+}
+
+impl AccessRule {
+    fn networks(&self) -> Result<(bool, Vec<IpNet>), String> {
+        let (allow, value) = match self {
+            AccessRule::Allow(value) => (true, value),
+            AccessRule::Deny(value) => (false, value),
+        };
+        Ok((allow, parse_networks(value)?))
+    }
+}
+
+/// Parses a single address, a CIDR range or `all`
+fn parse_networks(value: &str) -> Result<Vec<IpNet>, String> {
+    if value == "all" {
+        return Ok(vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()]);
+    }
+    match value.parse::<IpNet>() {
+        Ok(network) => Ok(vec![network]),
+        Err(_) => value.parse::<IpAddr>().map(|ip| vec![IpNet::from(ip)]).map_err(|e| e.to_string()),
+    }
+}
+
+/// The client address of a request, IPv4 clients on dual stack listeners show up as IPv4 mapped IPv6 addresses
+fn parse_client_ip(client_ip: &str) -> Option<IpAddr> {
+    match client_ip.parse::<IpAddr>().ok()? {
+        IpAddr::V6(v6) => Some(v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4)),
+        ip => Some(ip),
+    }
+}
+
+/// CORS policy of a host, preflight requests are answered by mproxy
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CorsConfig {
+    /// Exact origins, `*`, single label wildcards (`https://*.example.com`) or regex patterns prefixed with `~`
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Option<Vec<String>>,
+    /// Defaults to the headers requested by the preflight request
+    pub allowed_headers: Option<Vec<String>>,
+    pub exposed_headers: Option<Vec<String>>,
+    pub allow_credentials: Option<bool>,
+    pub max_age_secs: Option<u64>,
+    #[serde(skip)]
+    pub origin_patterns: Vec<Regex>,
+}
+
+impl CorsConfig {
+    pub fn allowed_methods(&self) -> String {
+        self.allowed_methods
+            .as_ref()
+            .map_or("GET, HEAD, POST, PUT, PATCH, DELETE".to_string(), |methods| methods.join(", "))
+    }
+
+    /// Never with `*`, browsers refuse it and reflecting every origin instead would let any site read credentialed responses
+    pub fn allow_credentials(&self) -> bool {
+        self.allow_credentials.unwrap_or(false) && !self.allows_any_origin()
+    }
+
+    pub fn allows_any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|allowed| allowed == "*")
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|allowed| {
+            allowed == "*"
+                || allowed == origin
+                || !allowed.starts_with('~') && allowed.split_once("*.").is_some_and(|(scheme, parent)| {
+                    // Exactly one label in place of the wildcard
+                    origin
+                        .strip_prefix(scheme)
+                        .and_then(|host| host.strip_suffix(parent))
+                        .and_then(|label| label.strip_suffix('.'))
+                        .is_some_and(|label| !label.is_empty() && !label.contains('.'))
+                })
+        }) || self.origin_patterns.iter().any(|pattern| pattern.is_match(origin))
+    }
+
+    /// The value of Access-Control-Allow-Origin, credentials are never allowed together with `*`
+    pub fn allow_origin_value<'a>(&self, origin: &'a str) -> &'a str {
+        if self.allows_any_origin() { "*" } else { origin }
+    }
+}
+
+/// Strict-Transport-Security of a host, all responses of mproxy are sent over TLS
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct HstsConfig {
+    pub enabled: Option<bool>,
+    pub max_age_secs: Option<u64>,
+    pub include_subdomains: Option<bool>,
+    pub preload: Option<bool>,
+}
+
+impl HstsConfig {
+    fn merge(&self, base: &HstsConfig) -> HstsConfig {
+        HstsConfig {
+            enabled: self.enabled.or(base.enabled),
+            max_age_secs: self.max_age_secs.or(base.max_age_secs),
+            include_subdomains: self.include_subdomains.or(base.include_subdomains),
+            preload: self.preload.or(base.preload),
+        }
+    }
+
+    /// The header value, `None` when disabled
+    pub fn value(&self) -> Option<String> {
+        if !self.enabled.unwrap_or(true) {
+            return None;
+        }
+        let mut value = format!("max-age={}", self.max_age_secs.unwrap_or(31536000));
+        if self.include_subdomains.unwrap_or(false) {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload.unwrap_or(false) {
+            value.push_str("; preload");
+        }
+        Some(value)
+    }
+}
+
+/// Security response headers, enabled with defaults for all hosts. An empty value turns a header off
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SecurityHeadersConfig {
+    pub enabled: Option<bool>,
+    pub hsts: Option<HstsConfig>,
+    /// Sends `X-Content-Type-Options: nosniff`
+    pub content_type_options: Option<bool>,
+    pub frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    /// Not sent by default, a policy only fits a specific application
+    pub content_security_policy: Option<String>,
+}
+
+impl SecurityHeadersConfig {
+    /// The settings of self, falling back to the ones of base for each unset field
+    pub fn merge(&self, base: &SecurityHeadersConfig) -> SecurityHeadersConfig {
+        let hsts = match (&self.hsts, &base.hsts) {
+            (Some(hsts), Some(base_hsts)) => Some(hsts.merge(base_hsts)),
+            (hsts, base_hsts) => hsts.clone().or(base_hsts.clone()),
+        };
+        SecurityHeadersConfig {
+            enabled: self.enabled.or(base.enabled),
+            hsts,
+            content_type_options: self.content_type_options.or(base.content_type_options),
+            frame_options: self.frame_options.clone().or(base.frame_options.clone()),
+            referrer_policy: self.referrer_policy.clone().or(base.referrer_policy.clone()),
+            content_security_policy: self.content_security_policy.clone().or(base.content_security_policy.clone()),
+        }
+    }
+
+    /// The headers to send with their values
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        if !self.enabled.unwrap_or(true) {
+            return Vec::new();
+        }
+        let mut headers = Vec::new();
+        if let Some(hsts) = self.hsts.clone().unwrap_or_default().value() {
+            headers.push(("Strict-Transport-Security", hsts));
+        }
+        if self.content_type_options.unwrap_or(true) {
+            headers.push(("X-Content-Type-Options", "nosniff".to_string()));
+        }
+        let frame_options = self.frame_options.as_deref().unwrap_or("SAMEORIGIN");
+        let referrer_policy = self.referrer_policy.as_deref().unwrap_or("strict-origin-when-cross-origin");
+        let content_security_policy = self.content_security_policy.as_deref().unwrap_or("");
+        for (name, value) in [
+            ("X-Frame-Options", frame_options),
+            ("Referrer-Policy", referrer_policy),
+            ("Content-Security-Policy", content_security_policy),
+        ] {
+            if !value.is_empty() {
+                headers.push((name, value.to_string()));
+            }
+        }
+        headers
+    }
+}
+
+/// The files of an error page, browsers get the HTML and API clients the JSON variant when both exist
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ErrorPage {
+    pub html: Option<String>,
+    pub json: Option<String>,
+}
+
+impl ErrorPage {
+    /// The file and content type of the preferred variant, falling back to the other one
+    pub fn variant(&self, prefers_json: bool) -> Option<(&str, &'static str)> {
+        let html = self.html.as_deref().map(|html| (html, "text/html; charset=utf-8"));
+        let json = self.json.as_deref().map(|json| (json, "application/json"));
+        if prefers_json { json.or(html) } else { html.or(json) }
+    }
+}
+
+/// Error pages by status code (e.g. `"502"`), `"connect"` for failed upstream connections
+/// and `"default"` for all other error statuses
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct ErrorPagesConfig(pub BTreeMap<String, ErrorPage>);
+
+impl ErrorPagesConfig {
+    pub fn page(&self, status: u16, connect_error: bool) -> Option<&ErrorPage> {
+        connect_error
+            .then(|| self.0.get("connect"))
+            .flatten()
+            .or_else(|| self.0.get(&status.to_string()))
+            .or_else(|| self.0.get("default"))
+    }
+}
+
+/// Maintenance mode of a host, also switched on by a file named like the host in the maintenance directory
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MaintenanceConfig {
+    pub enabled: Option<bool>,
+    /// HTML page sent with the 503 response
+    pub page: Option<String>,
+    pub retry_after_secs: Option<u64>,
+    /// Addresses or CIDR ranges that still reach the upstream
+    pub allow: Option<Vec<String>>,
+    #[serde(skip)]
+    pub allow_networks: Vec<IpNet>,
+}
+
+impl MaintenanceConfig {
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after_secs.unwrap_or(300)
+    }
+
+    pub fn is_allowed(&self, client_ip: &str) -> bool {
+        parse_client_ip(client_ip).is_some_and(|ip| self.allow_networks.iter().any(|network| network.contains(&ip)))
+    }
+}
+
+/// Where cached responses are stored
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheStorage {
+    #[default]
+    Memory,
+    Disk,
+}
+
+/// Response caching of a host or location, honoring Cache-Control, Expires and Vary of the upstream
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CacheConfig {
+    /// Defaults to true when the section is present
+    pub enabled: Option<bool>,
+    #[serde(default)]
+    pub storage: CacheStorage,
+    /// Freshness of cacheable responses without Cache-Control or Expires, they are not cached without it
+    pub default_ttl_secs: Option<u32>,
+    /// Larger responses are not cached
+    pub max_object_size: Option<usize>,
+    /// How long an expired response may be served while it is revalidated in the background
+    pub stale_while_revalidate_secs: Option<u32>,
+    /// How long an expired response may be served when the upstream fails or answers with 5xx
+    pub stale_if_error_secs: Option<u32>,
+    /// Response header with the (space or comma separated) tags the cached response can be purged by
+    pub tag_header: Option<String>,
+}
+
+impl CacheConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    pub fn stale_while_revalidate_secs(&self) -> u32 {
+        self.stale_while_revalidate_secs.unwrap_or(0)
+    }
+
+    pub fn stale_if_error_secs(&self) -> u32 {
+        self.stale_if_error_secs.unwrap_or(0)
+    }
+
+    pub fn tag_header(&self) -> &str {
+        self.tag_header.as_deref().unwrap_or("Surrogate-Key")
+    }
+}
+
+/// What requests are counted together by a rate limit
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    #[default]
+    ClientIp,
+    /// The value of the header named in `header`
+    Header,
+    /// The `X-API-Key` header or the bearer token of the `Authorization` header
+    ApiKey,
+}
+
+/// Limits the requests per key within a period, requests without a key value are counted by client IP
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    /// Burst size and number of requests per period
+    pub requests: u32,
+    pub period_secs: Option<u64>,
+    #[serde(default)]
+    pub key: RateLimitKey,
+    pub header: Option<String>,
+}
+
+impl RateLimitConfig {
+    /// At least one request, `0` is rejected when the config is loaded
+    pub fn requests(&self) -> u32 {
+        self.requests.max(1)
+    }
+
+    pub fn period(&self) -> Duration {
+        Duration::from_secs(self.period_secs.unwrap_or(1).max(1))
+    }
+}
+
+/// HTTP Basic authentication against a htpasswd file with bcrypt or argon2 hashes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BasicAuthConfig {
+    pub htpasswd_file: String,
+    pub realm: Option<String>,
+    /// Path prefixes that are passed without authentication, e.g. health checks
+    pub exempt_paths: Option<Vec<String>>,
+}
+
+impl BasicAuthConfig {
+    pub fn realm(&self) -> &str {
+        self.realm.as_deref().unwrap_or("Restricted")
+    }
+
+    /// Expects a path from `normalize_request_path`
+    pub fn is_exempt(&self, path: &str) -> bool {
+        self.exempt_paths
+            .as_ref()
+            .is_some_and(|paths| paths.iter().any(|p| path_has_prefix(path, p)))
+    }
+}
+
+/// Whether `path` is `prefix` or below it, whole segments only: `/api` matches `/api/v1` but not `/apiv1`
+pub fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix.trim_end_matches('/'))
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Decodes the `%XX` escapes of a request path, `None` for incomplete escapes or a result that is not UTF-8
+pub fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// The percent-decoded request path with empty segments removed, which locations and auth exemptions are matched
+/// against. `None` for invalid encodings and `.` / `..` segments, the upstream could resolve those to another path
+pub fn normalize_request_path(path: &str) -> Option<String> {
+    let decoded = percent_decode(path)?;
+    // Some upstreams treat backslashes as separators as well
+    if decoded.split(['/', '\\']).any(|segment| segment == "." || segment == "..") {
+        return None;
+    }
+    let mut normalized = String::with_capacity(decoded.len());
+    for segment in decoded.split('/').filter(|segment| !segment.is_empty()) {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() || decoded.ends_with('/') {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// Percent-encodes the bytes of a decoded path that are not allowed in a URI path
+pub fn encode_request_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b'!' | b'$' | b'&' | b'\''
+            | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' | b':' | b'@' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Rewrites the path of requests before they are sent upstream, applied in the order
+/// `strip_prefix`, `regex` / `replacement`, `add_prefix`. The query string is kept
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RewriteConfig {
+    pub strip_prefix: Option<String>,
+    pub add_prefix: Option<String>,
+    pub regex: Option<String>,
+    /// Replacement of the `regex` matches, capture groups are referenced as `$1` or `${name}`
+    pub replacement: Option<String>,
+    #[serde(skip)]
+    pub compiled_regex: Option<Regex>,
+}
+
+impl RewriteConfig {
+    /// Rewrites the normalized request path, the same one the location was matched against, and returns it
+    /// percent-encoded. `None` for paths `normalize_request_path` rejects
+    pub fn rewrite_path(&self, path: &str) -> Option<String> {
+        let mut path = normalize_request_path(path)?;
+        if let Some(prefix) = self.strip_prefix.as_deref().filter(|p| !p.is_empty()) {
+            if let Some(stripped) = path.strip_prefix(prefix.trim_end_matches('/')) {
+                // Only whole segments are stripped: /grafana but not /grafana2
+                if stripped.is_empty() || stripped.starts_with('/') {
+                    path = stripped.to_string();
+                }
+            }
+        }
+        if let Some(regex) = &self.compiled_regex {
+            path = regex
+                .replace_all(&path, self.replacement.as_deref().unwrap_or(""))
+                .into_owned();
+        }
+        if let Some(prefix) = &self.add_prefix {
+            path = format!("{}{}", prefix.trim_end_matches('/'), path);
+        }
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+        Some(encode_request_path(&path))
+    }
+}
+
+/// A path based routing rule inside a host
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Location {
+    pub path: String,
+    #[serde(default, rename = "match")]
+    pub match_type: LocationMatch,
+    /// Overrides the upstreams of the host for matching requests
+    pub upstream_address: Option<String>,
+    pub upstreams: Option<Vec<Upstream>>,
+    pub static_files: Option<StaticFilesConfig>,
+    /// Applied after the header rules of the host
+    pub headers: Option<HeadersConfig>,
+    pub rewrite: Option<RewriteConfig>,
+    /// Replaces the basic auth of the host for matching requests
+    pub basic_auth: Option<BasicAuthConfig>,
+    /// Replaces the rate limit of the host for matching requests
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Replaces the cache settings of the host for matching requests
+    pub cache: Option<CacheConfig>,
+    #[serde(skip)]
+    pub path_regex: Option<Regex>,
+}
+
+impl Location {
+    pub fn has_upstreams(&self) -> bool {
+        self.upstream_address.is_some() || self.upstreams.as_ref().is_some_and(|u| !u.is_empty())
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        match self.match_type {
+            LocationMatch::Prefix => path_has_prefix(path, &self.path),
+            LocationMatch::Exact => path == self.path,
+            LocationMatch::Regex => self.path_regex.as_ref().is_some_and(|r| r.is_match(path)),
+        }
+    }
+}
+
+impl HostConfig {
+    /// The host name and all aliases
+    pub fn host_names(&self) -> Vec<String> {
+        let mut host_names = vec![self.host_name.clone()];
+        if let Some(aliases) = &self.aliases {
+            host_names.extend(aliases.iter().cloned());
+        }
+        host_names
+    }
+
+    /// Whether requests for `server_name` should be redirected to the canonical host name
+    pub fn redirects_alias(&self, server_name: &str) -> bool {
+        self.redirect_aliases.unwrap_or(false)
+            && server_name != self.host_name
+            // Wildcards and patterns have no single canonical name to redirect to
+            && !self.host_name.starts_with("*.")
+            && !self.host_name.starts_with('~')
+    }
+
+    /// The static files served for a request, a location with own static files or upstreams overrides the host
+    pub fn static_files_for<'a>(&'a self, location: Option<&'a Location>) -> Option<&'a StaticFilesConfig> {
+        match location {
+            Some(location) if location.static_files.is_some() => location.static_files.as_ref(),
+            Some(location) if location.has_upstreams() => None,
+            _ => self.static_files.as_ref(),
+        }
+    }
+
+    /// The basic auth of a request, the one of a location overrides the host
+    pub fn basic_auth_for<'a>(&'a self, location: Option<&'a Location>) -> Option<&'a BasicAuthConfig> {
+        location
+            .and_then(|l| l.basic_auth.as_ref())
+            .or(self.basic_auth.as_ref())
+    }
+
+    /// Whether the client address passes the access rules. Without a matching rule access is allowed,
+    /// addresses that cannot be parsed are only allowed when there are no rules
+    pub fn is_access_allowed(&self, client_ip: &str) -> bool {
+        if self.access_networks.is_empty() {
+            return true;
+        }
+        let Some(ip) = parse_client_ip(client_ip) else {
+            return false;
+        };
+        self.access_networks
+            .iter()
+            .find(|(_, networks)| networks.iter().any(|network| network.contains(&ip)))
+            .is_none_or(|(allow, _)| *allow)
+    }
+
+    /// The rate limit of a request and the name its counters are kept under,
+    /// a location with its own rate limit is counted separately from the host
+    pub fn rate_limit_for<'a>(&'a self, location: Option<&'a Location>) -> Option<(String, &'a RateLimitConfig)> {
+        match location.and_then(|l| l.rate_limit.as_ref().map(|r| (l, r))) {
+            Some((location, rate_limit)) => Some((format!("{}|{}", self.host_name, location.path), rate_limit)),
+            None => self.rate_limit.as_ref().map(|rate_limit| (self.host_name.clone(), rate_limit)),
+        }
+    }
+
+    /// The cache settings of a request, the one of a location overrides the host
+    pub fn cache_for<'a>(&'a self, location: Option<&'a Location>) -> Option<&'a CacheConfig> {
+        location
+            .and_then(|l| l.cache.as_ref())
+            .or(self.cache.as_ref())
+            .filter(|cache| cache.is_enabled())
+    }
+
+    /// The file that puts the host in maintenance while it exists
+    pub fn maintenance_marker(&self) -> PathBuf {
+        PathBuf::from(maintenance_path()).join(&self.host_name)
+    }
+
+    /// The maintenance settings when the host is in maintenance, either by config or by the marker file
+    /// `<data path>/maintenance/<host name>` (defaults apply when the host has no maintenance section).
+    /// `marker_exists` checks the marker file, it is only called when maintenance is not enabled by config
+    pub fn active_maintenance(&self, marker_exists: impl FnOnce(&Path) -> bool) -> Option<MaintenanceConfig> {
+        let enabled = self.maintenance.as_ref().is_some_and(|m| m.enabled.unwrap_or(false));
+        if enabled || marker_exists(&self.maintenance_marker()) {
+            return Some(self.maintenance.clone().unwrap_or_default());
+        }
+        None
+    }
+
+    /// The header configs of a request, the one of the host first
+    pub fn headers_for<'a>(&'a self, location: Option<&'a Location>) -> Vec<&'a HeadersConfig> {
+        self.headers
+            .iter()
+            .chain(location.and_then(|l| l.headers.as_ref()))
+            .collect()
+    }
+
+    pub fn certificate_name(&self) -> &str {
+        self.certificate.as_deref().unwrap_or(&self.host_name)
+    }
+
+    /// Compiles the regex patterns of the host, invalid patterns are logged and never match
+    pub fn compile_patterns(&mut self) {
+        let host_name = self.host_name.clone();
+        if let Some(upstream_tls) = &self.upstream_tls {
+            let mut targets = self.upstreams_for(None);
+            for location in self.locations.iter().flatten().filter(|l| l.has_upstreams()) {
+                targets.extend(self.upstreams_for(Some(location)));
+            }
+            for target in targets.iter().filter(|target| upstream_tls.needs_sni(target)) {
+                error!("Upstream [{}] of host [{}] has no host name, upstream_tls needs an sni to verify it - not connecting", target.address, host_name);
+            }
+        }
+        self.access_networks.clear();
+        for rule in self.access.iter().flatten() {
+            match rule.networks() {
+                Ok(networks) => self.access_networks.push(networks),
+                Err(e) => error!("Invalid access rule {:?} for host [{}]: {}", rule, host_name, e),
+            }
+        }
+        let location_rate_limits = self.locations.iter().flatten().filter_map(|l| l.rate_limit.as_ref());
+        if self.rate_limit.iter().chain(location_rate_limits).any(|rate_limit| rate_limit.requests == 0) {
+            error!("Rate limit of host [{}] allows no requests, using 1", host_name);
+        }
+        if let Some(cors) = &mut self.cors {
+            cors.origin_patterns.clear();
+            for pattern in cors.allowed_origins.iter().filter_map(|origin| origin.strip_prefix('~')) {
+                match Regex::new(pattern) {
+                    Ok(regex) => cors.origin_patterns.push(regex),
+                    Err(e) => error!("Invalid CORS origin pattern [{}] for host [{}]: {}", pattern, host_name, e),
+                }
+            }
+            if cors.allows_any_origin() && cors.allow_credentials.unwrap_or(false) {
+                error!("CORS of host [{}] allows any origin, allow_credentials is ignored", host_name);
+            }
+        }
+        if let Some(maintenance) = &mut self.maintenance {
+            maintenance.allow_networks.clear();
+            for allow in maintenance.allow.iter().flatten() {
+                match parse_networks(allow) {
+                    Ok(networks) => maintenance.allow_networks.extend(networks),
+                    Err(e) => error!("Invalid maintenance allow [{}] for host [{}]: {}", allow, host_name, e),
+                }
+            }
+        }
+        if let Some(locations) = &mut self.locations {
+            for location in locations.iter_mut().filter(|l| l.match_type == LocationMatch::Regex) {
+                match Regex::new(&location.path) {
+                    Ok(regex) => location.path_regex = Some(regex),
+                    Err(e) => error!("Invalid location regex [{}] for host [{}]: {}", location.path, host_name, e),
+                }
+            }
+            for location in locations.iter_mut() {
+                let Some(rewrite) = &mut location.rewrite else { continue };
+                if let Some(pattern) = &rewrite.regex {
+                    match Regex::new(pattern) {
+                        Ok(regex) => rewrite.compiled_regex = Some(regex),
+                        Err(e) => error!("Invalid rewrite regex [{}] for location [{}] of host [{}]: {}", pattern, location.path, host_name, e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Finds the location for a request path, using the same precedence as nginx:
+    /// an exact match wins, otherwise the longest matching prefix is remembered and
+    /// the first matching regex (in config order) takes over from it.
+    pub fn find_location(&self, path: &str) -> Option<&Location> {
+        let locations = self.locations.as_ref()?;
+        if let Some(exact) = locations
+            .iter()
+            .find(|l| l.match_type == LocationMatch::Exact && l.matches(path))
+        {
+            return Some(exact);
+        }
+        let longest_prefix = locations
+            .iter()
+            .filter(|l| l.match_type == LocationMatch::Prefix && l.matches(path))
+            .max_by_key(|l| l.path.len());
+        locations
+            .iter()
+            .find(|l| l.match_type == LocationMatch::Regex && l.matches(path))
+            .or(longest_prefix)
+    }
+
+    /// The upstream targets for a request, a location with its own upstreams replaces the ones of the host
+    pub fn upstreams_for(&self, location: Option<&Location>) -> Vec<Upstream> {
+        match location.filter(|l| l.has_upstreams()) {
+            Some(location) => Self::collect_upstreams(&location.upstream_address, &location.upstreams),
+            None => Self::collect_upstreams(&self.upstream_address, &self.upstreams),
+        }
+    }
+
+    /// Key of the upstream pool serving a request, locations without own upstreams share the pool of the host
+    pub fn pool_key(&self, location: Option<&Location>) -> String {
+        match location.filter(|l| l.has_upstreams()) {
+            Some(location) => format!("{}|{}", self.host_name, location.path),
+            None => self.host_name.clone(),
+        }
+    }
+
+    fn collect_upstreams(upstream_address: &Option<String>, upstreams: &Option<Vec<Upstream>>) -> Vec<Upstream> {
+        let mut targets: Vec<Upstream> = Vec::new();
+        if let Some(address) = upstream_address {
+            targets.push(Upstream::new(address.clone()));
+        }
+        if let Some(upstreams) = upstreams {
+            targets.extend(upstreams.iter().cloned());
+        }
+        targets
+    }
+}
+
+/// What happens to requests for host names that are not configured
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownHostAction {
+    /// Closes the connection without a response
+    #[default]
+    Close,
+    /// Responds with `status` and the optional `page`
+    Status,
+    /// Proxies the request to the upstreams of the default host
+    Fallback,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UnknownHostConfig {
+    #[serde(default)]
+    pub action: UnknownHostAction,
+    pub status: Option<u16>,
+    /// HTML file sent as body of the `status` action
+    pub page: Option<String>,
+}
+
+impl UnknownHostConfig {
+    pub fn status(&self) -> u16 {
+        self.status.unwrap_or(421)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HostConfigList {
+    /// Host whose certificate is served for unknown server names and that unknown hosts fall back to
+    pub default_host: Option<String>,
+    pub unknown_host: Option<UnknownHostConfig>,
+    /// Error pages of all hosts, used when a host has no page for a status
+    pub error_pages: Option<ErrorPagesConfig>,
+    /// Security headers of all hosts, hosts can override single settings
+    pub security_headers: Option<SecurityHeadersConfig>,
     pub host_configs: Vec<HostConfig>,
 }
 
 impl Clone for HostConfigList {
     fn clone(&self) -> Self {
         HostConfigList {
+            default_host: self.default_host.clone(),
+            unknown_host: self.unknown_host.clone(),
+            error_pages: self.error_pages.clone(),
+            security_headers: self.security_headers.clone(),
             host_configs: self.host_configs.clone(),
         }
     }
@@ -27,6 +1151,8 @@ impl Clone for HostConfigList {
 #[derive(Debug)]
 pub struct HostsConfigLoader {
     pub config_list: Mutex<HostConfigList>,
+    /// Modification time of hosts.toml when it was last loaded
+    modified: Option<SystemTime>,
 }
 
 impl HostsConfigLoader {
@@ -37,25 +1163,56 @@ impl HostsConfigLoader {
         }
     }
 
+    fn modified(hosts_conf_path: &str) -> Option<SystemTime> {
+        fs::metadata(hosts_conf_path).and_then(|m| m.modified()).ok()
+    }
+
+    fn try_load_config_list(hosts_conf_path: &str) -> Result<HostConfigList, String> {
+        let content = fs::read_to_string(hosts_conf_path).map_err(|e| e.to_string())?;
+        let mut config_list: HostConfigList = toml::from_str(content.as_str()).map_err(|e| e.to_string())?;
+        config_list.host_configs.iter_mut().for_each(|host_config| host_config.compile_patterns());
+        Ok(config_list)
+    }
+
     fn load_config_list(hosts_conf_path: String) -> HostConfigList {
-        toml::from_str(fs::read_to_string(&hosts_conf_path).unwrap().as_str()).unwrap()
+        HostsConfigLoader::try_load_config_list(&hosts_conf_path).unwrap()
     }
 }
 
 impl HostsConfigLoader {
+    // Panics without a hosts.toml, which a Default implementation should not
+    #[allow(clippy::new_without_default)]
     pub fn new() -> HostsConfigLoader {
         let hosts_conf_path = HostsConfigLoader::resolve_hosts_conf_path();
         if !fs::exists(&hosts_conf_path).unwrap() {
             panic!("Host config file does not exist: [{}]", &hosts_conf_path);
         }
         HostsConfigLoader {
+            modified: HostsConfigLoader::modified(&hosts_conf_path),
             config_list: Mutex::from(HostsConfigLoader::load_config_list(hosts_conf_path)),
         }
     }
 
-    pub fn refresh_hosts_config(&mut self) {
-        self.config_list =
-            HostsConfigLoader::load_config_list(HostsConfigLoader::resolve_hosts_conf_path()).into();
+    /// Reloads hosts.toml when it changed, returns whether a new config was loaded.
+    /// A config that cannot be loaded is logged and the previous one stays active
+    pub fn refresh_hosts_config(&mut self) -> bool {
+        let hosts_conf_path = HostsConfigLoader::resolve_hosts_conf_path();
+        let modified = HostsConfigLoader::modified(&hosts_conf_path);
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        match HostsConfigLoader::try_load_config_list(&hosts_conf_path) {
+            Ok(config_list) => {
+                info!("Reloaded host config: [{}]", hosts_conf_path);
+                self.config_list = config_list.into();
+                true
+            }
+            Err(e) => {
+                error!("Cannot reload host config [{}], keeping the previous one: {}", hosts_conf_path, e);
+                false
+            }
+        }
     }
 
     pub fn load(&self) -> HostConfigList {
@@ -63,3 +1220,354 @@ impl HostsConfigLoader {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_request_path_decodes_and_collapses_slashes() {
+        assert_eq!(normalize_request_path("/").as_deref(), Some("/"));
+        assert_eq!(normalize_request_path("").as_deref(), Some("/"));
+        assert_eq!(normalize_request_path("//admin//users/").as_deref(), Some("/admin/users/"));
+        assert_eq!(normalize_request_path("/%61dmin").as_deref(), Some("/admin"));
+        assert_eq!(normalize_request_path("/a%20b").as_deref(), Some("/a b"));
+    }
+
+    #[test]
+    fn normalize_request_path_rejects_dot_segments_and_bad_encodings() {
+        assert_eq!(normalize_request_path("/public/../admin"), None);
+        assert_eq!(normalize_request_path("/public/%2e%2e/admin"), None);
+        assert_eq!(normalize_request_path("/public%2f..%2fadmin"), None);
+        assert_eq!(normalize_request_path("/public\\..\\admin"), None);
+        assert_eq!(normalize_request_path("/./admin"), None);
+        assert_eq!(normalize_request_path("/admin%"), None);
+        assert_eq!(normalize_request_path("/admin%zz"), None);
+        assert_eq!(normalize_request_path("/%ff"), None);
+        // Dots inside a segment are fine
+        assert_eq!(normalize_request_path("/files/..hidden/a.b").as_deref(), Some("/files/..hidden/a.b"));
+    }
+
+    #[test]
+    fn path_has_prefix_matches_whole_segments() {
+        assert!(path_has_prefix("/api/health", "/api/health"));
+        assert!(path_has_prefix("/api/health/live", "/api/health"));
+        assert!(!path_has_prefix("/api/healthz", "/api/health"));
+        assert!(path_has_prefix("/api/health", "/api/"));
+        assert!(path_has_prefix("/anything", "/"));
+        assert!(!path_has_prefix("/ap", "/api"));
+    }
+
+    fn host_with_access(rules: &str) -> HostConfig {
+        let mut host_config: HostConfig = toml::from_str(&format!("host_name = \"example.com\"\naccess = {}", rules)).unwrap();
+        host_config.compile_patterns();
+        host_config
+    }
+
+    #[test]
+    fn parse_client_ip_unmaps_ipv4_mapped_addresses() {
+        assert_eq!(parse_client_ip("::ffff:10.0.0.1"), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(parse_client_ip("10.0.0.1"), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(parse_client_ip("2001:db8::1"), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(parse_client_ip(""), None);
+        assert_eq!(parse_client_ip("10.0.0.1:443"), None);
+    }
+
+    #[test]
+    fn access_rules_first_match_decides() {
+        let host_config = host_with_access(r#"[{ deny = "10.0.0.13" }, { allow = "10.0.0.0/8" }, { deny = "all" }]"#);
+        assert!(!host_config.is_access_allowed("10.0.0.13"));
+        assert!(host_config.is_access_allowed("10.1.2.3"));
+        assert!(!host_config.is_access_allowed("192.168.1.1"));
+        assert!(!host_config.is_access_allowed("2001:db8::1"));
+        // IPv4 clients on a dual stack listener
+        assert!(host_config.is_access_allowed("::ffff:10.1.2.3"));
+        assert!(!host_config.is_access_allowed("::ffff:10.0.0.13"));
+    }
+
+    #[test]
+    fn access_without_matching_rule_is_allowed() {
+        let host_config = host_with_access(r#"[{ deny = "192.168.0.0/16" }, { deny = "2001:db8::/32" }]"#);
+        assert!(host_config.is_access_allowed("10.0.0.1"));
+        assert!(host_config.is_access_allowed("2001:db9::1"));
+        assert!(!host_config.is_access_allowed("192.168.7.7"));
+        assert!(!host_config.is_access_allowed("2001:db8::7"));
+    }
+
+    #[test]
+    fn access_with_rules_denies_unparseable_clients() {
+        assert!(!host_with_access(r#"[{ allow = "all" }]"#).is_access_allowed("unknown"));
+        assert!(host_with_access("[]").is_access_allowed("unknown"));
+    }
+
+    #[test]
+    fn basic_auth_exemptions_match_whole_segments() {
+        let config = BasicAuthConfig {
+            htpasswd_file: "/dev/null".to_string(),
+            realm: None,
+            exempt_paths: Some(vec!["/api/health".to_string()]),
+        };
+        assert!(config.is_exempt("/api/health"));
+        assert!(config.is_exempt("/api/health/ready"));
+        assert!(!config.is_exempt("/api/healthz"));
+        assert!(!config.is_exempt("/api"));
+    }
+
+    #[test]
+    fn upstream_tls_needs_sni_for_targets_without_host_name() {
+        let tls: UpstreamTlsConfig = toml::from_str("").unwrap();
+        assert!(tls.needs_sni(&Upstream::new("10.0.0.1:443".to_string())));
+        assert!(tls.needs_sni(&Upstream::new("[2001:db8::1]:443".to_string())));
+        assert!(tls.needs_sni(&Upstream::new("unix:/run/app.sock".to_string())));
+        assert!(!tls.needs_sni(&Upstream::new("backend.internal:443".to_string())));
+
+        let with_sni: UpstreamTlsConfig = toml::from_str("sni = \"backend.internal\"").unwrap();
+        assert!(!with_sni.needs_sni(&Upstream::new("10.0.0.1:443".to_string())));
+        let insecure: UpstreamTlsConfig = toml::from_str("insecure_skip_verify = true").unwrap();
+        assert!(!insecure.needs_sni(&Upstream::new("10.0.0.1:443".to_string())));
+        let disabled: UpstreamTlsConfig = toml::from_str("enabled = false").unwrap();
+        assert!(!disabled.needs_sni(&Upstream::new("10.0.0.1:443".to_string())));
+    }
+
+    fn rewrite(strip_prefix: Option<&str>, regex: Option<(&str, &str)>) -> RewriteConfig {
+        RewriteConfig {
+            strip_prefix: strip_prefix.map(str::to_string),
+            regex: regex.map(|(pattern, _)| pattern.to_string()),
+            replacement: regex.map(|(_, replacement)| replacement.to_string()),
+            compiled_regex: regex.map(|(pattern, _)| Regex::new(pattern).unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rewrite_strips_whole_prefix_segments() {
+        let rewrite = rewrite(Some("/grafana"), None);
+        assert_eq!(rewrite.rewrite_path("/grafana/d/abc").as_deref(), Some("/d/abc"));
+        assert_eq!(rewrite.rewrite_path("/grafana").as_deref(), Some("/"));
+        assert_eq!(rewrite.rewrite_path("/grafana2/x").as_deref(), Some("/grafana2/x"));
+    }
+
+    #[test]
+    fn rewrite_replaces_regex_matches() {
+        let rewrite = rewrite(None, Some((r"^/users/([0-9]+)/profile$", "/api/v2/profiles/$1")));
+        assert_eq!(rewrite.rewrite_path("/users/42/profile").as_deref(), Some("/api/v2/profiles/42"));
+        assert_eq!(rewrite.rewrite_path("/users/42/settings").as_deref(), Some("/users/42/settings"));
+    }
+
+    #[test]
+    fn rewrite_works_on_the_normalized_path() {
+        let strip = rewrite(Some("/grafana"), None);
+        assert_eq!(strip.rewrite_path("//grafana/x").as_deref(), Some("/x"));
+        assert_eq!(strip.rewrite_path("/%67rafana/x").as_deref(), Some("/x"));
+        assert_eq!(strip.rewrite_path("/grafana/../admin"), None);
+        let regex = rewrite(None, Some((r"^/users/([0-9]+)/profile$", "/api/v2/profiles/$1")));
+        assert_eq!(regex.rewrite_path("//users/1/profile").as_deref(), Some("/api/v2/profiles/1"));
+        // Decoded characters that are not allowed in a path are encoded again
+        assert_eq!(strip.rewrite_path("/grafana/a%20b%3F%25").as_deref(), Some("/a%20b%3F%25"));
+    }
+
+    fn cors(allowed_origins: &[&str]) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: allowed_origins.iter().map(|origin| origin.to_string()).collect(),
+            origin_patterns: allowed_origins
+                .iter()
+                .filter_map(|origin| origin.strip_prefix('~'))
+                .map(|pattern| Regex::new(pattern).unwrap())
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cors_allows_exact_and_pattern_origins() {
+        let cors = cors(&["https://app.example.com", "~^https://[a-z]+\\.internal$"]);
+        assert!(cors.allows_origin("https://app.example.com"));
+        assert!(!cors.allows_origin("http://app.example.com"));
+        assert!(!cors.allows_origin("https://app.example.com.evil.net"));
+        assert!(cors.allows_origin("https://admin.internal"));
+        assert!(!cors.allows_origin("https://admin.internal.evil.net"));
+        assert!(!cors.allows_any_origin());
+        assert_eq!(cors.allow_origin_value("https://app.example.com"), "https://app.example.com");
+    }
+
+    #[test]
+    fn cors_wildcards_match_one_label_or_any_origin() {
+        let subdomains = cors(&["https://*.example.com"]);
+        assert!(subdomains.allows_origin("https://app.example.com"));
+        assert!(!subdomains.allows_origin("https://example.com"));
+        assert!(!subdomains.allows_origin("https://a.b.example.com"));
+        assert!(!subdomains.allows_origin("http://app.example.com"));
+        assert!(!subdomains.allows_origin("https://app.evilexample.com"));
+
+        let mut any = cors(&["*"]);
+        any.allow_credentials = Some(true);
+        assert!(any.allows_origin("https://anything.test"));
+        assert_eq!(any.allow_origin_value("https://anything.test"), "*");
+        assert!(!any.allow_credentials());
+    }
+
+    #[test]
+    fn security_headers_have_defaults_and_can_be_turned_off() {
+        let defaults: SecurityHeadersConfig = toml::from_str("").unwrap();
+        assert_eq!(
+            defaults.headers(),
+            vec![
+                ("Strict-Transport-Security", "max-age=31536000".to_string()),
+                ("X-Content-Type-Options", "nosniff".to_string()),
+                ("X-Frame-Options", "SAMEORIGIN".to_string()),
+                ("Referrer-Policy", "strict-origin-when-cross-origin".to_string()),
+            ]
+        );
+
+        let custom: SecurityHeadersConfig = toml::from_str(
+            "frame_options = \"\"\ncontent_type_options = false\ncontent_security_policy = \"default-src 'self'\"\n[hsts]\nenabled = false",
+        )
+        .unwrap();
+        assert_eq!(
+            custom.headers(),
+            vec![
+                ("Referrer-Policy", "strict-origin-when-cross-origin".to_string()),
+                ("Content-Security-Policy", "default-src 'self'".to_string()),
+            ]
+        );
+
+        let disabled: SecurityHeadersConfig = toml::from_str("enabled = false").unwrap();
+        assert!(disabled.headers().is_empty());
+    }
+
+    #[test]
+    fn security_headers_merge_falls_back_to_the_global_settings() {
+        let global: SecurityHeadersConfig =
+            toml::from_str("frame_options = \"DENY\"\n[hsts]\nmax_age_secs = 600\npreload = true").unwrap();
+        let host: SecurityHeadersConfig =
+            toml::from_str("referrer_policy = \"no-referrer\"\n[hsts]\ninclude_subdomains = true").unwrap();
+        let merged = host.merge(&global);
+        assert_eq!(merged.frame_options.as_deref(), Some("DENY"));
+        assert_eq!(merged.referrer_policy.as_deref(), Some("no-referrer"));
+        // The HSTS settings are merged field by field
+        assert_eq!(
+            merged.hsts.unwrap().value().as_deref(),
+            Some("max-age=600; includeSubDomains; preload")
+        );
+
+        let disabled_host: SecurityHeadersConfig = toml::from_str("enabled = false").unwrap();
+        assert!(disabled_host.merge(&global).headers().is_empty());
+        assert!(!SecurityHeadersConfig::default().merge(&global).headers().is_empty());
+    }
+
+    fn host_with_locations(locations: &str) -> HostConfig {
+        let mut host_config: HostConfig = toml::from_str(&format!("host_name = \"example.com\"\n{}", locations)).unwrap();
+        host_config.compile_patterns();
+        host_config
+    }
+
+    fn location_path<'a>(host_config: &'a HostConfig, path: &str) -> Option<&'a str> {
+        host_config.find_location(path).map(|location| location.path.as_str())
+    }
+
+    #[test]
+    fn find_location_prefers_the_longest_whole_segment_prefix() {
+        let host_config = host_with_locations(
+            r#"
+            [[locations]]
+            path = "/api"
+            [[locations]]
+            path = "/api/v2/"
+            [[locations]]
+            path = "/"
+            "#,
+        );
+        assert_eq!(location_path(&host_config, "/api/v2/users"), Some("/api/v2/"));
+        assert_eq!(location_path(&host_config, "/api/v2"), Some("/api/v2/"));
+        assert_eq!(location_path(&host_config, "/api/v1"), Some("/api"));
+        assert_eq!(location_path(&host_config, "/api"), Some("/api"));
+        // Prefixes match whole segments only
+        assert_eq!(location_path(&host_config, "/apiv2"), Some("/"));
+        assert_eq!(location_path(&host_config, "/api/v2x"), Some("/api"));
+        assert_eq!(location_path(&host_with_locations(""), "/api"), None);
+    }
+
+    #[test]
+    fn find_location_prefers_exact_then_regex_over_prefix() {
+        let host_config = host_with_locations(
+            r#"
+            [[locations]]
+            path = "/images"
+            [[locations]]
+            path = "\\.png$"
+            match = "regex"
+            [[locations]]
+            path = "/images/logo.png"
+            match = "exact"
+            "#,
+        );
+        assert_eq!(location_path(&host_config, "/images/logo.png"), Some("/images/logo.png"));
+        assert_eq!(location_path(&host_config, "/images/icon.png"), Some("\\.png$"));
+        assert_eq!(location_path(&host_config, "/images/icon.gif"), Some("/images"));
+        assert_eq!(location_path(&host_config, "/images/logo.png/x"), Some("/images"));
+    }
+
+    #[test]
+    fn outlier_ejection_time_doubles_up_to_the_maximum() {
+        let config: OutlierDetectionConfig = toml::from_str("ejection_secs = 10\nmax_ejection_secs = 60").unwrap();
+        let secs: Vec<u64> = (0..5).map(|ejections| config.ejection_time(ejections).as_secs()).collect();
+        assert_eq!(secs, vec![10, 20, 40, 60, 60]);
+        assert_eq!(config.ejection_time(u32::MAX), Duration::from_secs(60));
+
+        let defaults: OutlierDetectionConfig = toml::from_str("").unwrap();
+        assert_eq!(defaults.ejection_time(0), Duration::from_secs(30));
+        assert_eq!(defaults.ejection_time(10), Duration::from_secs(300));
+        // The maximum never shortens the first ejection
+        let short_max: OutlierDetectionConfig = toml::from_str("ejection_secs = 120\nmax_ejection_secs = 60").unwrap();
+        assert_eq!(short_max.ejection_time(3), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn redirect_location_keeps_path_and_query_by_default() {
+        let redirect: RedirectConfig = toml::from_str("target = \"https://example.com/\"").unwrap();
+        assert_eq!(redirect.location("/a/b", Some("x=1")), "https://example.com/a/b?x=1");
+        assert_eq!(redirect.location("/", None), "https://example.com/");
+        assert_eq!(redirect.status(), 301);
+
+        let target_only: RedirectConfig =
+            toml::from_str("target = \"https://example.com/landing\"\nstatus = 308\npreserve_path = false\npreserve_query = false")
+                .unwrap();
+        assert_eq!(target_only.location("/a/b", Some("x=1")), "https://example.com/landing");
+        assert_eq!(target_only.status(), 308);
+
+        let path_only: RedirectConfig =
+            toml::from_str("target = \"https://example.com/new\"\nstatus = 200\npreserve_query = false").unwrap();
+        assert_eq!(path_only.location("/a", Some("x=1")), "https://example.com/new/a");
+        assert_eq!(path_only.status(), 301);
+    }
+
+    #[test]
+    fn error_pages_prefer_connect_then_status_then_default() {
+        let pages: ErrorPagesConfig = toml::from_str(
+            r#"
+            "502" = { html = "/pages/502.html" }
+            "connect" = { html = "/pages/down.html", json = "/pages/down.json" }
+            "default" = { json = "/pages/error.json" }
+            "#,
+        )
+        .unwrap();
+        let html = |status, connect_error| pages.page(status, connect_error).and_then(|page| page.html.as_deref());
+        assert_eq!(html(502, true), Some("/pages/down.html"));
+        assert_eq!(html(502, false), Some("/pages/502.html"));
+        assert_eq!(pages.page(404, false).and_then(|page| page.json.as_deref()), Some("/pages/error.json"));
+
+        let without_default: ErrorPagesConfig = toml::from_str(r#""502" = { html = "/pages/502.html" }"#).unwrap();
+        assert!(without_default.page(503, false).is_none());
+        // Connect errors without a connect page use the page of their status
+        assert!(without_default.page(502, true).is_some());
+    }
+
+    #[test]
+    fn error_page_variant_falls_back_to_the_other_format() {
+        let both = ErrorPage { html: Some("/e.html".to_string()), json: Some("/e.json".to_string()) };
+        assert_eq!(both.variant(false), Some(("/e.html", "text/html; charset=utf-8")));
+        assert_eq!(both.variant(true), Some(("/e.json", "application/json")));
+        let html_only = ErrorPage { html: Some("/e.html".to_string()), json: None };
+        assert_eq!(html_only.variant(true), Some(("/e.html", "text/html; charset=utf-8")));
+        assert_eq!(ErrorPage { html: None, json: None }.variant(false), None);
+    }
+}
@@ -114,26 +114,16 @@ fn open_and_parse_cert(path: PathBuf) -> Certificate {
                         }
                       };
                       let sub_ext = ext.parsed_extension();
-                      match sub_ext {
-                        ParsedExtension::SubjectAlternativeName(sub) => {
-                          let mut host_names: Vec<String> = Vec::new();
-                          for name in sub.general_names.clone() {
-                            match name {
-                              GeneralName::DNSName(name) => {
-                                info!("Name: {}", name);
-                                host_names.push(name.to_string());
-                              },
-                              _ => {
-
-                              }
-                            }
+                      if let ParsedExtension::SubjectAlternativeName(sub) = sub_ext {
+                        let mut host_names: Vec<String> = Vec::new();
+                        for name in sub.general_names.clone() {
+                          if let GeneralName::DNSName(name) = name {
+                            info!("Name: {}", name);
+                            host_names.push(name.to_string());
                           }
-                          return_cert.set_host_names(host_names);
-                        },
-                        _ => {
-
                         }
-                      };
+                        return_cert.set_host_names(host_names);
+                      }
                     },
                     Err(e) => {
                       error!("Error parsing cert.pem: {}", e);
@@ -170,12 +160,9 @@ fn parse_hostname(in_str: &str) -> String {
 
 // Renew is actually requesting a new certificate
 // but we get the info from the certificate object itself
-pub fn renew_certificate(domain: &String, email: &String, staging: bool){
-  if let Some(cert) = find_certificate(domain.clone()) {
-    let mut aliases: Vec<String> = Vec::new();
-    if cert.host_names.is_some() {
-      aliases = cert.host_names.unwrap();
-    }
+pub fn renew_certificate(domain: &str, email: &str, staging: bool){
+  if let Some(cert) = find_certificate(domain.to_string()) {
+    let aliases: Vec<String> = cert.host_names.unwrap_or_default();
     match request_certificate(domain, email, &aliases, staging){
       Ok(_) => {
         println!("Certificate renewed: {}", domain);
@@ -189,7 +176,7 @@ pub fn renew_certificate(domain: &String, email: &String, staging: bool){
   }
 }
 
-pub fn request_certificate(domain: &String, email: &String, aliases: &Vec<String>, staging: bool) -> Result<(),Error> {
+pub fn request_certificate(domain: &str, email: &str, aliases: &[String], staging: bool) -> Result<(),Error> {
 
   let url = if staging {
     DirectoryUrl::LetsEncryptStaging
@@ -201,9 +188,9 @@ pub fn request_certificate(domain: &String, email: &String, aliases: &Vec<String
 
   let dir = Directory::from_url(persist, url)?;
 
-  let acc = dir.account(&email)?;
+  let acc = dir.account(email)?;
   let v = aliases.iter().map(|s| &s[..]).collect::<Vec<&str>>();
-  let mut order_new = acc.new_order(&domain, v.as_slice())?;
+  let mut order_new = acc.new_order(domain, v.as_slice())?;
 
   let order_csr = loop {
     if let Some(order_csr) = order_new.confirm_validations(){
@@ -236,7 +223,7 @@ pub fn request_certificate(domain: &String, email: &String, aliases: &Vec<String
 }
 
 // Takes Letsencrypt Certificate and stores it in the cert store
-fn le_cert_to_cert_store(le_cert: acme_v2::Certificate, domain: &String, aliases: &Vec<String>){
+fn le_cert_to_cert_store(le_cert: acme_v2::Certificate, domain: &str, aliases: &[String]){
   const BEGIN_MARKER: &str = "-----BEGIN CERTIFICATE-----";
   const END_MARKER: &str = "-----END CERTIFICATE-----";
   let mut certificates = Vec::new();
@@ -254,11 +241,11 @@ fn le_cert_to_cert_store(le_cert: acme_v2::Certificate, domain: &String, aliases
     }
   }
 
-  let mut new_cert = Certificate::new(domain.clone());
+  let mut new_cert = Certificate::new(domain.to_string());
   new_cert.set_private_key(le_cert.private_key().to_string());
   new_cert.set_certificate(certificates[0].clone());
   new_cert.set_full_chain(le_cert.certificate().parse().unwrap());
-  new_cert.set_host_names(aliases.clone());
+  new_cert.set_host_names(aliases.to_vec());
 
   let cert_dest_path = PathBuf::from(cert_path()).join(new_cert.get_host_name());
   if !cert_dest_path.exists() {
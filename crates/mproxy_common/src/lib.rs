@@ -19,3 +19,12 @@ pub fn acme_challenge_path() -> String {
 pub fn acme_path() -> String {
     format!("{}/acme", data_path())
 }
+
+pub fn cache_path() -> String {
+    std::env::var("MPROXY_CACHE_PATH")
+        .unwrap_or_else(|_| format!("{}/cache", data_path()))
+}
+
+pub fn maintenance_path() -> String {
+    format!("{}/maintenance", data_path())
+}